@@ -6,13 +6,16 @@
 //!
 //! Module for compiled .b91 files.
 //!
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fmt::{Display, Formatter};
 use std::str::{FromStr, Lines};
 
 /// Representation of a .b91 file. Useful for loading compiled files.
-/// You can construct this from .b91 file contents with [from_str](#method.from_str).
-#[derive(Clone, Default)]
+/// You can construct this from .b91 file contents with [from_str](#method.from_str), and turn it
+/// back into file contents with [to_string](#method.to_string).
+/// More fields may be added, so outside this crate, build one with [new](#method.new).
+#[derive(Clone, Default, PartialEq, Debug)]
+#[non_exhaustive]
 pub struct B91 {
     /// Code segment struct
     pub code_segment: B91Segment,
@@ -22,10 +25,26 @@ pub struct B91 {
     pub symbol_table: HashMap<String, i32>,
     /// Comments: <address, comment>.
     pub comments: HashMap<usize, String>,
+    /// Debug info: <address, where it came from in the source>.
+    pub debug_info: HashMap<usize, SourceLocation>,
+    /// Symbols that are constants (EQU) instead of labels. Empty if not known, which is the
+    /// default. See [CompileOptions::constants_section](crate::compiler::CompileOptions::constants_section).
+    pub constants: HashSet<String>,
+}
+
+/// Position in source code. Used to map addresses back to source in debug info.
+#[derive(Clone, PartialEq, Debug)]
+pub struct SourceLocation {
+    /// Source file name. Can be empty if the compiler wasn't given one.
+    pub file: String,
+    /// Line number, starting from 1.
+    pub line: usize,
+    /// Byte offset within the line, starting from 0.
+    pub column: usize,
 }
 
 /// Represents either the data segment, or code segment.
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Debug)]
 pub struct B91Segment {
     /// First address in this segment
     pub start: i32,
@@ -47,6 +66,9 @@ pub enum B91ParseError {
     SymbolParseError(String),
     CommentParseError(String),
     MultipleComment(usize),
+    DebugInfoParseError(String),
+    MultipleDebugInfo(usize),
+    ConstantParseError(String),
 }
 
 impl Display for B91ParseError {
@@ -82,6 +104,15 @@ impl Display for B91ParseError {
             B91ParseError::MultipleComment(addr) => {
                 write!(f, "Multiple comments for same line: '{addr}")
             }
+            B91ParseError::DebugInfoParseError(line) => {
+                write!(f, "Failed to parse debug info: '{line}")
+            }
+            B91ParseError::MultipleDebugInfo(addr) => {
+                write!(f, "Multiple debug info entries for same address: '{addr}")
+            }
+            B91ParseError::ConstantParseError(line) => {
+                write!(f, "Failed to parse constant: '{line}")
+            }
         }
     }
 }
@@ -95,6 +126,11 @@ impl FromStr for B91 {
     /// - `___data___`
     /// - `___symboltable___`
     /// - `___end___` (must be last)
+    ///
+    /// These optional sections may follow the symbol table, in any order:
+    /// - `___comments___`
+    /// - `___debug___`
+    /// - `___constants___`
     fn from_str(b91: &str) -> Result<Self, Self::Err> {
         let mut lines = b91.lines();
 
@@ -112,6 +148,8 @@ impl FromStr for B91 {
         let mut data_segment: Option<B91Segment> = None;
         let mut symbol_table: Option<HashMap<String, i32>> = None;
         let mut comments: Option<HashMap<usize, String>> = None;
+        let mut debug_info: Option<HashMap<usize, SourceLocation>> = None;
+        let mut constants: Option<HashSet<String>> = None;
 
         // Loop through sections
         loop {
@@ -135,12 +173,38 @@ impl FromStr for B91 {
                             if symbol_table.is_some() {
                                 return Err(B91ParseError::RepeatSection("___symboltable___".into()));
                             }
-                            let table;
-                            let has_comments;
-                            (table, has_comments) = parse_symbol_table(&mut lines)?;
+                            let (table, mut next_section) = parse_symbol_table(&mut lines)?;
                             symbol_table = Some(table);
-                            if has_comments {
-                                comments = Some(parse_comments_section(&mut lines)?);
+
+                            // Optional sections after symbol table
+                            loop {
+                                match next_section {
+                                    "___comments___" => {
+                                        if comments.is_some() {
+                                            return Err(B91ParseError::RepeatSection("___comments___".into()));
+                                        }
+                                        let (section, next) = parse_comments_section(&mut lines)?;
+                                        comments = Some(section);
+                                        next_section = next;
+                                    }
+                                    "___debug___" => {
+                                        if debug_info.is_some() {
+                                            return Err(B91ParseError::RepeatSection("___debug___".into()));
+                                        }
+                                        let (section, next) = parse_debug_section(&mut lines)?;
+                                        debug_info = Some(section);
+                                        next_section = next;
+                                    }
+                                    "___constants___" => {
+                                        if constants.is_some() {
+                                            return Err(B91ParseError::RepeatSection("___constants___".into()));
+                                        }
+                                        let (section, next) = parse_constants_section(&mut lines)?;
+                                        constants = Some(section);
+                                        next_section = next;
+                                    }
+                                    _ => break,
+                                }
                             }
                             break;
                         }
@@ -161,19 +225,122 @@ impl FromStr for B91 {
         if symbol_table.is_none() {
             return Err(B91ParseError::SectionMissing("___symboltable___".into()));
         }
-        if comments.is_none() {
-            comments = Some(HashMap::new())
-        }
 
         Ok(B91 {
             code_segment: code_segment.unwrap(),
             data_segment: data_segment.unwrap(),
             symbol_table: symbol_table.unwrap(),
-            comments: comments.unwrap(),
+            comments: comments.unwrap_or_default(),
+            debug_info: debug_info.unwrap_or_default(),
+            constants: constants.unwrap_or_default(),
         })
     }
 }
 
+impl Display for B91 {
+    /// Write .b91 file contents.
+    /// Symbols are sorted by name and comments by address, so the output is always the same.
+    /// The `___comments___`, `___debug___` and `___constants___` sections are left out if they
+    /// would be empty.
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "___b91___")?;
+        writeln!(f, "___code___")?;
+        write!(f, "{}", self.code_segment)?;
+        writeln!(f, "___data___")?;
+        write!(f, "{}", self.data_segment)?;
+
+        writeln!(f, "___symboltable___")?;
+        let mut symbols: Vec<(&String, &i32)> = self.symbol_table.iter().collect();
+        symbols.sort();
+        for (symbol, value) in symbols {
+            writeln!(f, "{symbol} {value}")?;
+        }
+
+        if !self.comments.is_empty() {
+            writeln!(f, "___comments___")?;
+            let mut comments: Vec<(&usize, &String)> = self.comments.iter().collect();
+            comments.sort();
+            for (address, comment) in comments {
+                writeln!(f, "{address} {comment}")?;
+            }
+        }
+
+        if !self.debug_info.is_empty() {
+            writeln!(f, "___debug___")?;
+            let mut debug_info: Vec<(&usize, &SourceLocation)> = self.debug_info.iter().collect();
+            debug_info.sort_by_key(|(address, _)| **address);
+            for (address, location) in debug_info {
+                writeln!(f, "{address} {} {} {}", location.line, location.column, location.file)?;
+            }
+        }
+
+        if !self.constants.is_empty() {
+            writeln!(f, "___constants___")?;
+            let mut constants: Vec<&String> = self.constants.iter().collect();
+            constants.sort();
+            for name in constants {
+                writeln!(f, "{name}")?;
+            }
+        }
+
+        writeln!(f, "___end___")
+    }
+}
+
+impl B91 {
+    /// A program without comments, debug info or known constants.
+    pub fn new(code_segment: B91Segment, data_segment: B91Segment, symbol_table: HashMap<String, i32>) -> Self {
+        B91 {
+            code_segment,
+            data_segment,
+            symbol_table,
+            ..Default::default()
+        }
+    }
+
+    /// Write .b91 file contents exactly the way Titokone does. Comments, debug info and constants
+    /// are left out, and symbols are listed in the order Titokone's symbol table, a Java HashMap, has them.
+    pub fn to_titokone_string(&self) -> String {
+        let mut b91 = format!("___b91___\n___code___\n{}___data___\n{}___symboltable___\n", self.code_segment, self.data_segment);
+        for symbol in java_hashmap_order(self.symbol_table.keys()) {
+            b91 += &format!("{} {}\n", symbol, self.symbol_table[symbol]);
+        }
+        b91 + "___end___\n"
+    }
+}
+
+/// Order in which a Java HashMap<String, _> iterates over these keys: by bucket, and within a
+/// bucket in insertion order. Insertion order isn't known here, so keys sharing a bucket are sorted.
+fn java_hashmap_order<'a>(keys: impl Iterator<Item = &'a String>) -> Vec<&'a String> {
+    let mut keys: Vec<&String> = keys.collect();
+    keys.sort();
+
+    // Table starts at 16 buckets, and doubles whenever it's over 3/4 full.
+    let mut capacity: usize = 16;
+    while keys.len() > capacity * 3 / 4 {
+        capacity *= 2;
+    }
+
+    // String.hashCode(), spread the way HashMap.hash() does it.
+    let bucket = |key: &String| {
+        let hash = key.encode_utf16().fold(0i32, |hash, c| hash.wrapping_mul(31).wrapping_add(c as i32)) as u32;
+        ((hash ^ (hash >> 16)) as usize) & (capacity - 1)
+    };
+    keys.sort_by_key(|key| bucket(key));
+    keys
+}
+
+impl Display for B91Segment {
+    /// Write segment offsets and contents, same format as [from_lines](#method.from_lines) reads.
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{} {}", self.start, self.end)?;
+        for value in &self.content {
+            writeln!(f, "{value}")?;
+        }
+        Ok(())
+    }
+}
+
 impl Default for B91Segment {
     fn default() -> Self {
         B91Segment {
@@ -237,18 +404,18 @@ impl B91Segment {
     }
 }
 
-/// Result Ok: (symbol_table, has_comments)
-fn parse_symbol_table(lines: &mut Lines) -> Result<(HashMap<String, i32>, bool), B91ParseError> {
+/// Headers of sections that can come after the symbol table, and ___end___.
+const TRAILING_SECTIONS: [&str; 4] = ["___comments___", "___debug___", "___constants___", "___end___"];
+
+/// Result Ok: (symbol_table, header of the next section)
+fn parse_symbol_table<'a>(lines: &mut Lines<'a>) -> Result<(HashMap<String, i32>, &'a str), B91ParseError> {
     let mut symbol_table = HashMap::new();
     loop {
         match lines.next() {
             Some(line) => {
                 // Exit
-                if line == "___end___" {
-                    return Ok((symbol_table, false));
-                }
-                if line == "___comments___" {
-                    return Ok((symbol_table, true));
+                if TRAILING_SECTIONS.contains(&line) {
+                    return Ok((symbol_table, line));
                 }
                 // Split
                 let words: Vec<String> = line.split_whitespace().map(str::to_string).collect();
@@ -271,27 +438,22 @@ fn parse_symbol_table(lines: &mut Lines) -> Result<(HashMap<String, i32>, bool),
     }
 }
 
-fn parse_comments_section(lines: &mut Lines) -> Result<HashMap<usize, String>, B91ParseError> {
+/// Result Ok: (comments, header of the next section)
+fn parse_comments_section<'a>(lines: &mut Lines<'a>) -> Result<(HashMap<usize, String>, &'a str), B91ParseError> {
     let mut comments = HashMap::new();
     loop {
         match lines.next() {
             Some(line) => {
                 // Exit
-                if line == "___end___" {
-                    break;
+                if TRAILING_SECTIONS.contains(&line) {
+                    return Ok((comments, line));
                 }
-                println!("made it here");
 
                 // Split
-                let addr_str;
-                let comment;
-                match line.split_once(' ') {
-                    Some((before, after)) => {
-                        addr_str = before;
-                        comment = after.to_owned()
-                    }
+                let (addr_str, comment) = match line.split_once(' ') {
+                    Some((before, after)) => (before, after.to_owned()),
                     None => return Err(B91ParseError::CommentParseError(format!("Failed to split line, '{line}")))
-                }
+                };
                 // Add Comment
                 match addr_str.parse::<usize>() {
                     Ok(address) => {
@@ -306,9 +468,70 @@ fn parse_comments_section(lines: &mut Lines) -> Result<HashMap<usize, String>, B
             None => return Err(B91ParseError::End),
         }
     }
-    Ok(comments)
 }
 
+/// Debug info lines are: `address line column file`. File is last, because it may contain spaces.
+/// Result Ok: (debug_info, header of the next section)
+fn parse_debug_section<'a>(lines: &mut Lines<'a>) -> Result<(HashMap<usize, SourceLocation>, &'a str), B91ParseError> {
+    let mut debug_info = HashMap::new();
+    loop {
+        match lines.next() {
+            Some(line) => {
+                // Exit
+                if TRAILING_SECTIONS.contains(&line) {
+                    return Ok((debug_info, line));
+                }
+
+                // Split
+                let words: Vec<&str> = line.splitn(4, ' ').collect();
+                if words.len() != 4 {
+                    return Err(B91ParseError::DebugInfoParseError(format!("words.len() != 4, '{line}")));
+                }
+                let address = words[0].parse::<usize>();
+                let source_line = words[1].parse::<usize>();
+                let column = words[2].parse::<usize>();
+                match (address, source_line, column) {
+                    (Ok(address), Ok(source_line), Ok(column)) => {
+                        if debug_info.contains_key(&address) {
+                            return Err(B91ParseError::MultipleDebugInfo(address));
+                        }
+                        debug_info.insert(address, SourceLocation {
+                            file: words[3].to_string(),
+                            line: source_line,
+                            column,
+                        });
+                    }
+                    (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => {
+                        return Err(B91ParseError::DebugInfoParseError(format!("{e}, '{line}")));
+                    }
+                }
+            }
+            None => return Err(B91ParseError::End),
+        }
+    }
+}
+
+/// Constants section has one symbol name per line.
+/// Result Ok: (constants, header of the next section)
+fn parse_constants_section<'a>(lines: &mut Lines<'a>) -> Result<(HashSet<String>, &'a str), B91ParseError> {
+    let mut constants = HashSet::new();
+    loop {
+        match lines.next() {
+            Some(line) => {
+                // Exit
+                if TRAILING_SECTIONS.contains(&line) {
+                    return Ok((constants, line));
+                }
+                let words: Vec<&str> = line.split_whitespace().collect();
+                if words.len() != 1 {
+                    return Err(B91ParseError::ConstantParseError(format!("words.len() != 1, '{line}")));
+                }
+                constants.insert(words[0].to_string());
+            }
+            None => return Err(B91ParseError::End),
+        }
+    }
+}
 
 #[cfg(test)]
 mod tests {
@@ -465,7 +688,7 @@ ___comments___
 
         assert_eq!(result.comments.get(&0).unwrap(), "comment0");
         assert_eq!(result.comments.get(&1).unwrap(), "comment1");
-        assert!(result.comments.get(&2).is_none());
+        assert!(!result.comments.contains_key(&2));
         assert_eq!(result.comments.get(&3).unwrap(), "this is the third comment");
         assert_eq!(result.comments.get(&4).unwrap(), " this comment contains both a leading and a trailing space ");
         assert_eq!(result.comments.len(), 4);
@@ -492,4 +715,171 @@ ___comments___
         let result = B91::from_str(input);
         assert!(result.is_err());
     }
+
+    #[test]
+    fn test_b91_to_string() {
+        let mut b91 = B91 {
+            code_segment: B91Segment { start: 0, end: 1, content: vec![52428801, 1891631115] },
+            data_segment: B91Segment { start: 2, end: 2, content: vec![-5] },
+            symbol_table: HashMap::new(),
+            comments: HashMap::new(),
+            debug_info: HashMap::new(),
+            constants: HashSet::new(),
+        };
+        b91.symbol_table.insert("x".into(), 2);
+        b91.symbol_table.insert("halt".into(), 11);
+        assert_eq!(b91.to_string(), "___b91___
+___code___
+0 1
+52428801
+1891631115
+___data___
+2 2
+-5
+___symboltable___
+halt 11
+x 2
+___end___
+");
+
+        b91.comments.insert(1, "stop".into());
+        b91.comments.insert(0, " load x ".into());
+        assert!(b91.to_string().ends_with("___comments___\n0  load x \n1 stop\n___end___\n"));
+    }
+
+    #[test]
+    fn test_b91_to_titokone_string() {
+        let mut b91 = B91 {
+            code_segment: B91Segment { start: 0, end: 1, content: vec![524288, 1891631115] },
+            data_segment: B91Segment { start: 2, end: 5, content: vec![2, 0, 0, 0] },
+            symbol_table: HashMap::new(),
+            comments: HashMap::from([(0, "comment".into())]),
+            debug_info: HashMap::new(),
+            constants: HashSet::new(),
+        };
+        for (symbol, value) in [("label", 0), ("variable", 2), ("array", 3), ("const", 1), ("halt", 11)] {
+            b91.symbol_table.insert(symbol.into(), value);
+        }
+
+        // This is what Titokone wrote for the same program.
+        assert_eq!(b91.to_titokone_string(), "___b91___
+___code___
+0 1
+524288
+1891631115
+___data___
+2 5
+2
+0
+0
+0
+___symboltable___
+halt 11
+const 1
+array 3
+variable 2
+label 0
+___end___
+");
+    }
+
+    #[test]
+    fn test_java_hashmap_order() {
+        // 13 keys is too many for 16 buckets, so the table grows to 32.
+        let keys: Vec<String> = (0..13).map(|i| format!("s{}", i)).collect();
+        let order: Vec<&str> = java_hashmap_order(keys.iter()).iter().map(|key| key.as_str()).collect();
+        assert_eq!(order, vec!["s3", "s4", "s5", "s6", "s7", "s8", "s9", "s11", "s10", "s12", "s0", "s1", "s2"]);
+    }
+
+    #[test]
+    fn test_b91_round_trip() {
+        let input = "___b91___
+___code___
+4 6
+101
+-202
+303
+___data___
+7 8
+1
+0
+___symboltable___
+Symbol1 1
+symbol0 0
+___comments___
+4 comment0
+5  comment with spaces 
+8 comment2
+___end___
+";
+        let b91 = B91::from_str(input).unwrap();
+        assert_eq!(b91.to_string(), input);
+        assert_eq!(B91::from_str(&b91.to_string()).unwrap(), b91);
+    }
+
+    #[test]
+    fn test_b91_from_str_debug_info() {
+        let input = "___b91___
+___code___
+0 1
+0
+0
+___data___
+2 1
+___symboltable___
+___debug___
+0 3 8 main.k91
+1 12 0 my lib.k91
+___comments___
+0 comment
+___end___
+";
+        let b91 = B91::from_str(input).unwrap();
+        assert_eq!(b91.debug_info.len(), 2);
+        assert_eq!(b91.debug_info.get(&0).unwrap(), &SourceLocation { file: "main.k91".into(), line: 3, column: 8 });
+        assert_eq!(b91.debug_info.get(&1).unwrap(), &SourceLocation { file: "my lib.k91".into(), line: 12, column: 0 });
+        assert_eq!(b91.comments.get(&0).unwrap(), "comment");
+
+        // Debug section is written after comments
+        assert_eq!(B91::from_str(&b91.to_string()).unwrap(), b91);
+        assert!(b91.to_string().ends_with("___debug___\n0 3 8 main.k91\n1 12 0 my lib.k91\n___end___\n"));
+    }
+
+    #[test]
+    fn test_b91_from_str_debug_info_repeat() {
+        let input = "___b91___
+___code___
+0 -1
+___data___
+0 -1
+___symboltable___
+___debug___
+0 3 8 main.k91
+0 4 8 main.k91
+___end___";
+        assert_eq!(B91::from_str(input), Err(B91ParseError::MultipleDebugInfo(0)));
+    }
+
+    #[test]
+    fn test_b91_from_str_constants() {
+        let input = "___b91___
+___code___
+0 -1
+___data___
+0 0
+5
+___symboltable___
+size 3
+x 0
+___constants___
+size
+___end___
+";
+        let b91 = B91::from_str(input).unwrap();
+        assert_eq!(b91.constants, HashSet::from(["size".to_string()]));
+        assert_eq!(b91.to_string(), input);
+
+        let input = input.replace("___constants___\nsize", "___constants___\nsize 3");
+        assert!(matches!(B91::from_str(&input), Err(B91ParseError::ConstantParseError(_))));
+    }
 }
@@ -1,118 +1,256 @@
 //! TTKTK - TTK-91 ToolKit
 //! Compiler executable
-use std::{env, fs};
-use std::fs::File;
-use std::io::Error;
-use std::io::Write;
+use std::fs;
+use std::io::{Read, Write};
 use std::path::PathBuf;
-use libttktk::compiler::compile;
+use std::process::ExitCode;
+use std::sync::Arc;
+use clap::{CommandFactory, FromArgMatches, Parser};
+use libttktk::compiler::{compile_full, CompileOptions, Diagnostic, FsResolver, Lint, TargetIsa};
 
-fn main() {
-    let mut args: Vec<String> = env::args().collect();
-    args.reverse();
+/// Source had errors.
+const EXIT_COMPILE_ERROR: u8 = 1;
+/// Bad command line. This is also what clap exits with.
+const EXIT_USAGE: u8 = 2;
+/// Couldn't read input or write output.
+const EXIT_IO_ERROR: u8 = 3;
 
-    // Skip first arg, which is program name
-    let _ = args.pop();
+/// Stands for stdin or stdout in file arguments.
+const STDIO: &str = "-";
 
-    if args.is_empty() {
-        println!("No arguments given.");
-        print_help();
-        return;
-    }
+/// TTKTK Assembler. Assembles TTK-91 .k91 source into .b91.
+#[derive(Parser, Debug)]
+#[command(name = "titoasm", version)]
+struct Args {
+    /// Source file. '-' reads from stdin.
+    input: String,
 
-    let input_path: String = args.pop().unwrap();
-    let mut output_path: Option<String> = None;
-
-    // Collect options
-    loop {
-        match args.pop() {
-            None => break,
-            Some(arg) => {
-                match arg.as_str() {
-
-                    // Output file
-                    "-o" => {
-                        match args.pop() {
-                            None => {
-                                print_err_no_arg(arg);
-                                return;
-                            }
-                            Some(outfile) => {
-                                match output_path {
-                                    None => output_path = Some(outfile),
-                                    Some(_) => {
-                                        print_err_opt_redefine(arg);
-                                        return;
-                                    }
-                                }
-                            }
-                        }
-                    }
-
-                    // Help
-                    "-h" => print_help(),
-
-                    // Invalid
-                    _ => {
-                        println!("Err: Invalid option '{}'", arg);
-                        return;
-                    }
-                }
-            }
+    /// Output file. '-' writes to stdout. Default is same as input, with extension changed to .b91,
+    /// or stdout if input is stdin.
+    #[arg(short, long, value_name = "FILE")]
+    output: Option<String>,
+
+    /// Also write a listing file, with addresses, contents, and source side by side. '-' writes to stdout.
+    #[arg(short, long, value_name = "FILE")]
+    listing: Option<String>,
+
+    /// Include debug info that maps addresses to source lines.
+    #[arg(short = 'g', long)]
+    debug_info: bool,
+
+    /// Define a constant for IF conditions and the program. Value defaults to 1.
+    #[arg(short = 'D', long = "define", value_name = "NAME[=VALUE]", value_parser = parse_define)]
+    defines: Vec<(String, i32)>,
+
+    /// Stop after n errors. 0 means no limit.
+    #[arg(long, value_name = "N", default_value_t = 50)]
+    error_limit: usize,
+
+    /// Target instruction set: classic or extended.
+    #[arg(long, value_name = "ISA", default_value = "extended")]
+    isa: TargetIsa,
+
+    /// Symbols are case-insensitive.
+    #[arg(long)]
+    ignore_case: bool,
+
+    /// Titokone compatibility: case-insensitive symbols, classic instructions only, and Titokone's output format.
+    #[arg(long)]
+    titokone: bool,
+
+    /// Enable a warning. 'no-<lint>' disables it, 'all' and 'none' set all of them, and 'error'
+    /// treats warnings as errors. All are enabled by default.
+    #[arg(short = 'W', value_name = "LINT")]
+    warnings: Vec<String>,
+
+    /// Only print errors.
+    #[arg(short, long)]
+    quiet: bool,
+}
+
+fn main() -> ExitCode {
+    let command = Args::command().after_help(lint_help());
+    let args = match Args::from_arg_matches(&command.get_matches()) {
+        Ok(args) => args,
+        Err(e) => e.exit(),
+    };
+
+    let mut options = CompileOptions {
+        error_limit: Some(args.error_limit).filter(|limit| *limit != 0),
+        debug_info: args.debug_info,
+        defines: args.defines.iter().cloned().collect(),
+        case_sensitive: !args.ignore_case,
+        target_isa: args.isa,
+        titokone: args.titokone,
+        ..Default::default()
+    };
+    for value in &args.warnings {
+        if let Err(e) = set_lint(&mut options, value) {
+            eprintln!("Err: Invalid value for '-W': {}", e);
+            return ExitCode::from(EXIT_USAGE);
         }
     }
 
-    // Open input file
-    let source;
-    match fs::read_to_string(&input_path) {
-        Ok(contents) => source = contents,
+    // Read input
+    let source = match read_input(&args.input) {
+        Ok(contents) => contents,
         Err(e) => {
-            print_err_inputfile(input_path, e);
-            return;
+            eprintln!("Err: Could not read input file {}: {}", args.input, e);
+            return ExitCode::from(EXIT_IO_ERROR);
         }
-    }
+    };
 
     // Compile
-    let output;
-    match compile(source) {
-        Ok(out) => output = out,
+    options.source_name = match args.input.as_str() {
+        STDIO => "<stdin>".to_string(),
+        path => path.to_string(),
+    };
+    options.file_resolver = Some(Arc::new(FsResolver));
+    let output = match compile_full(source, &options) {
+        Ok(out) => out,
         Err(e) => {
             print_err_compiler(e);
-            return;
+            return ExitCode::from(EXIT_COMPILE_ERROR);
+        }
+    };
+    if !args.quiet {
+        for warning in &output.warnings {
+            eprintln!("{}", warning);
+        }
+    }
+
+    // Write listing file
+    if let Some(path) = &args.listing {
+        if let Err(e) = write_output(path, &output.listing) {
+            eprintln!("Err: Could not write listing file {}: {}", path, e);
+            return ExitCode::from(EXIT_IO_ERROR);
         }
     }
 
     // Write output file
-    if output_path.is_none() {
-        let mut path = PathBuf::from(input_path);
-        path.set_extension("b91");
-        output_path = Some(path.into_os_string().into_string().unwrap());
+    let output_path = match (&args.output, args.input.as_str()) {
+        (Some(path), _) => path.clone(),
+        (None, STDIO) => STDIO.to_string(),
+        (None, input) => {
+            let mut path = PathBuf::from(input);
+            path.set_extension("b91");
+            path.to_string_lossy().into_owned()
+        }
+    };
+    let output = match options.titokone {
+        true => output.b91.to_titokone_string(),
+        false => output.b91.to_string(),
+    };
+    if let Err(e) = write_output(&output_path, &output) {
+        eprintln!("Err: Could not write output file {}: {}", output_path, e);
+        return ExitCode::from(EXIT_IO_ERROR);
+    }
+
+    // Status goes to stderr, so it doesn't end up in output written to stdout.
+    if !args.quiet {
+        eprintln!("Success!");
+    }
+    ExitCode::SUCCESS
+}
+
+/// List of lints, for the end of help.
+fn lint_help() -> String {
+    let names: Vec<&str> = Lint::ALL.iter().map(|lint| lint.name()).collect();
+    format!("Lints:\n  {}", names.join("\n  "))
+}
+
+/// Parse "NAME=value", or just "NAME" for 1.
+fn parse_define(define: &str) -> Result<(String, i32), String> {
+    let (name, value) = match define.split_once('=') {
+        Some((name, value)) => (name, value),
+        None => (define, "1"),
+    };
+    if name.is_empty() {
+        return Err("No name given".to_string());
+    }
+    match value.parse::<i32>() {
+        Ok(value) => Ok((name.to_string(), value)),
+        Err(e) => Err(format!("'{}': {}", value, e)),
     }
-    let mut file = File::create(output_path.unwrap()).unwrap();
-    let _ = write!(file, "{}", output);
-    println!("Success!");
 }
 
-fn print_help() {
-    println!("TTKTK Assembler]");
-    println!("Usage: titoasm [file] [options]...");
-    println!("Options:");
-    println!("-h | --help       Help");
-    println!("-o <file>         Specify output file. Default is same as input, with extension changed to .b91");
+/// Handle the value of "-W".
+fn set_lint(options: &mut CompileOptions, value: &str) -> Result<(), String> {
+    match value {
+        "all" => options.lints.extend(Lint::ALL),
+        "none" => options.lints.clear(),
+        "error" => options.warnings_as_errors = true,
+        _ => match value.strip_prefix("no-") {
+            Some(name) => {
+                options.lints.remove(&name.parse::<Lint>()?);
+            }
+            None => {
+                options.lints.insert(value.parse::<Lint>()?);
+            }
+        },
+    }
+    Ok(())
 }
 
-fn print_err_opt_redefine(opt: String) {
-    println!("Err: Option '{}' is already defined!", opt);
+/// Read a file, or stdin if path is "-".
+fn read_input(path: &str) -> std::io::Result<String> {
+    match path {
+        STDIO => {
+            let mut contents = String::new();
+            std::io::stdin().read_to_string(&mut contents)?;
+            Ok(contents)
+        }
+        _ => fs::read_to_string(path),
+    }
 }
 
-fn print_err_no_arg(opt: String) {
-    println!("Err: Not enough argument for '{}'", opt);
+/// Write a file, or stdout if path is "-".
+fn write_output(path: &str, contents: &str) -> std::io::Result<()> {
+    match path {
+        STDIO => std::io::stdout().write_all(contents.as_bytes()),
+        _ => fs::write(path, contents),
+    }
 }
 
-fn print_err_inputfile(file: String, e: Error) {
-    println!("Err: Could not read input file {}: {}", file, e)
+fn print_err_compiler(diagnostics: Vec<Diagnostic>) {
+    eprintln!("Err: Couldn't compile:");
+    for diagnostic in diagnostics {
+        eprintln!("{}", diagnostic)
+    }
 }
 
-fn print_err_compiler(e: String) {
-    println!("Err: Couldn't compile: {}", e)
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_args() {
+        Args::command().debug_assert();
+
+        let args = Args::try_parse_from(["titoasm", "-", "-o", "out.b91", "-D", "DEBUG", "-DLEVEL=2", "-Wno-unused-label", "-W", "error", "-q"]).unwrap();
+        assert_eq!(args.input, "-");
+        assert_eq!(args.output.as_deref(), Some("out.b91"));
+        assert_eq!(args.defines, vec![("DEBUG".to_string(), 1), ("LEVEL".to_string(), 2)]);
+        assert_eq!(args.warnings, vec!["no-unused-label", "error"]);
+        assert!(args.quiet);
+        assert_eq!(args.isa, TargetIsa::Extended);
+
+        assert!(Args::try_parse_from(["titoasm", "a.k91", "--isa", "modern"]).is_err());
+        assert!(Args::try_parse_from(["titoasm", "a.k91", "-D", "X=y"]).is_err());
+        assert!(Args::try_parse_from(["titoasm"]).is_err());
+    }
+
+    #[test]
+    fn test_set_lint() {
+        let mut options = CompileOptions::default();
+        set_lint(&mut options, "none").unwrap();
+        set_lint(&mut options, "jump-to-data").unwrap();
+        assert_eq!(options.lints.len(), 1);
+        set_lint(&mut options, "all").unwrap();
+        set_lint(&mut options, "no-unused-data").unwrap();
+        assert_eq!(options.lints.len(), Lint::ALL.len() - 1);
+        set_lint(&mut options, "error").unwrap();
+        assert!(options.warnings_as_errors);
+        assert!(set_lint(&mut options, "no-nope").is_err());
+    }
 }
@@ -6,13 +6,41 @@
 //!
 //! TiToMachine k91 assembly compiler.
 //!
+// Diagnostics are big, but they're only made when something is wrong.
+#![allow(clippy::result_large_err)]
 mod code_parser;
-
-use std::collections::HashMap;
+mod conditional;
+mod diagnostic;
+mod expression;
+mod file_resolver;
+mod lints;
+mod listing;
+mod macros;
+mod preprocessor;
+mod symbol_resolver;
+mod tokenizer;
+
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::ops::Range;
 use std::str::FromStr;
+use std::sync::Arc;
+use crate::b91::{B91, B91Segment, SourceLocation};
 use crate::compiler::code_parser::parse_instruction;
+use crate::compiler::conditional::is_conditional;
+use crate::compiler::expression::eval_expression;
+use crate::compiler::lints::run_lints;
+use crate::compiler::listing::create_listing;
+use crate::compiler::preprocessor::{MacroCall, preprocess, SourceLine};
+use crate::compiler::symbol_resolver::SymbolResolver;
+use crate::compiler::tokenizer::{parse_string_literal, Token, tokenize_line};
 use crate::instructions::{OpCode, Register};
 
+pub use diagnostic::{Diagnostic, ErrorCode, Severity};
+pub use file_resolver::{FileResolver, FsResolver};
+pub use lints::Lint;
+
 #[allow(dead_code)] // TODO: Not checked for anymore. Should be checked for symbol names.
 const FORBIDDEN_CHARS: [char; 6] = [
     '(', ')', // parentheses
@@ -21,7 +49,7 @@ const FORBIDDEN_CHARS: [char; 6] = [
     ':', // what was colon used for, again?
 ];
 
-#[derive(PartialEq)]
+#[derive(Clone, Copy, PartialEq, Debug)]
 enum Keyword {
     Directive,
     Const,
@@ -44,42 +72,326 @@ struct Symbol {
 }
 
 /// One of the first things that happens to a line of code is to be organized into this struct.
-/// Statement holds the code as Vec<String>, and knows some high-level information and metadata
+/// Statement holds the code as Vec<Token>, and knows some high-level information and metadata
 /// about it.
+#[derive(Clone, Debug)]
 struct Statement {
     pub statement_type: Keyword,
-    pub label: Option<String>,
+    pub label: Option<Token>,
     //
-    pub words: Vec<String>,
+    pub words: Vec<Token>,
     // Remaining keywords after label
+    pub file: String,
     pub line: usize,
-    #[allow(dead_code)] // Comments will be added to the output, eventually.
-    pub comment: Option<String>,
+    // Position in the preprocessed source.
+    pub index: usize,
+    // Where the statement is on the line, excluding comment.
+    pub columns: Range<usize>,
+    pub comment: Option<Token>,
+    // If the statement came from a macro, the calls that led here. Innermost first.
+    pub macro_calls: Vec<MacroCall>,
+    // Most recent global label at this statement. Local labels (".loop") are relative to it.
+    pub scope: Option<String>,
+    // Numeric label references used in this statement ("1b", "1f"), and the labels they point to.
+    pub numeric_refs: HashMap<String, String>,
+    // Symbols are case-insensitive, so names are folded to lowercase.
+    pub fold_case: bool,
+}
+
+impl Statement {
+    /// Create an error that points at this statement.
+    fn error(&self, code: ErrorCode, message: impl Into<String>) -> Diagnostic {
+        let error = Diagnostic::error(code, self.line, message)
+            .with_file(self.file.as_str())
+            .with_columns(self.columns.clone());
+        self.add_macro_notes(error)
+    }
+
+    /// Say which macro calls the statement came from, if any.
+    fn add_macro_notes(&self, mut diagnostic: Diagnostic) -> Diagnostic {
+        for call in &self.macro_calls {
+            diagnostic = diagnostic.with_note(call.note());
+        }
+        diagnostic
+    }
+
+    /// Comment without surrounding whitespace. None if there's no comment, or it's empty.
+    fn comment_text(&self) -> Option<String> {
+        let comment = self.comment.as_ref()?.text.trim();
+        if comment.is_empty() {
+            return None;
+        }
+        Some(comment.to_string())
+    }
+
+    /// Where this statement is in the source, for debug info.
+    fn location(&self) -> SourceLocation {
+        SourceLocation {
+            file: self.file.clone(),
+            line: self.line,
+            column: self.columns.start,
+        }
+    }
+
+    /// Full name of a symbol used in this statement: ".loop" becomes "main.loop" if the statement
+    /// is in the scope of "main", and "1b" becomes the name of the numeric label it points to.
+    /// If case is folded, builtins become uppercase and everything else lowercase.
+    /// Other names stay as they are.
+    fn qualify<'a>(&'a self, name: &'a str) -> Cow<'a, str> {
+        if let Some(target) = self.numeric_refs.get(name) {
+            return Cow::Borrowed(target);
+        }
+        let name = match &self.scope {
+            Some(scope) if name.starts_with('.') => Cow::Owned(format!("{}{}", scope, name)),
+            _ => Cow::Borrowed(name),
+        };
+        if !self.fold_case {
+            return name;
+        }
+        let upper = name.to_uppercase();
+        match str_to_builtin_const(&upper) {
+            Ok(_) => Cow::Owned(upper),
+            Err(_) => Cow::Owned(name.to_lowercase()),
+        }
+    }
+
+    /// Evaluate one of this statement's words as a constant expression.
+    /// Local symbols are qualified before they're passed to `lookup`.
+    fn eval_word(&self, word: &Token, lookup: impl Fn(&str) -> Option<i32>) -> Result<i64, Diagnostic> {
+        eval_expression(&word.text, |name| lookup(&self.qualify(name)))
+            .map_err(|e| self.error_at(&word.slice(e.columns), e.code, e.message))
+    }
+
+    /// Create an error that points at a single token of this statement.
+    fn error_at(&self, token: &Token, code: ErrorCode, message: impl Into<String>) -> Diagnostic {
+        let error = Diagnostic::error(code, self.line, message)
+            .with_file(self.file.as_str())
+            .with_columns(token.columns.clone())
+            .with_token(token.text.as_str());
+        self.add_macro_notes(error)
+    }
 }
 
-pub fn compile(source: String) -> Result<String, String> {
+/// Instruction set the compiler targets.
+#[derive(Copy, Clone, Default, PartialEq, Eq, Debug)]
+pub enum TargetIsa {
+    /// Classic TTK-91, as understood by Titokone.
+    Classic,
+    /// TiToMachine extended instruction set: classic, plus IEXIT, HLT and HCF.
+    #[default]
+    Extended,
+}
+
+impl FromStr for TargetIsa {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "classic" => Ok(TargetIsa::Classic),
+            "extended" => Ok(TargetIsa::Extended),
+            _ => Err(format!("Unknown instruction set: '{}'. Expected 'classic' or 'extended'.", s)),
+        }
+    }
+}
+
+/// Settings for the compiler.
+#[derive(Clone)]
+pub struct CompileOptions {
+    /// Stop after this many errors. None means no limit.
+    pub error_limit: Option<usize>,
+    /// Add a `___debug___` section that maps addresses back to source lines.
+    pub debug_info: bool,
+    /// Add a `___constants___` section that tells which symbols are EQU constants, so that the
+    /// disassembler can tell them apart from labels. Older readers and Titokone don't know it.
+    pub constants_section: bool,
+    /// Name of the source file, used in diagnostics, debug info, and to find included files.
+    pub source_name: String,
+    /// Where INCLUDE gets files from. None disables INCLUDE.
+    pub file_resolver: Option<Arc<dyn FileResolver>>,
+    /// Constants given from outside the source, such as `titoasm -D DEBUG=1`. They work like EQU
+    /// constants, and can be used in IF conditions anywhere in the file.
+    pub defines: HashMap<String, i32>,
+    /// Symbol names are case sensitive. If not, they're folded to lowercase, and builtin constants
+    /// can be written in any case.
+    pub case_sensitive: bool,
+    /// Instructions outside of this instruction set are errors.
+    pub target_isa: TargetIsa,
+    /// Titokone compatibility mode, for old course material. Symbols are case-insensitive, only the
+    /// classic instruction set is allowed, and [compile_with_options] writes .b91 exactly the way
+    /// Titokone does, with builtin constants that the program uses in the symbol table.
+    pub titokone: bool,
+    /// Lints that are checked. Their warnings don't stop the compile.
+    pub lints: HashSet<Lint>,
+    /// Treat warnings as errors.
+    pub warnings_as_errors: bool,
+}
+
+impl CompileOptions {
+    /// Are symbol names case sensitive, considering Titokone mode?
+    fn symbols_case_sensitive(&self) -> bool {
+        self.case_sensitive && !self.titokone
+    }
+
+    /// Defines, with names lowercase if symbols are case-insensitive.
+    fn effective_defines(&self) -> HashMap<String, i32> {
+        match self.symbols_case_sensitive() {
+            true => self.defines.clone(),
+            false => self.defines.iter().map(|(name, value)| (name.to_lowercase(), *value)).collect(),
+        }
+    }
+
+    /// Target instruction set, considering Titokone mode.
+    fn effective_target_isa(&self) -> TargetIsa {
+        match self.titokone {
+            true => TargetIsa::Classic,
+            false => self.target_isa,
+        }
+    }
+}
+
+impl Default for CompileOptions {
+    fn default() -> Self {
+        CompileOptions {
+            error_limit: Some(50),
+            debug_info: false,
+            constants_section: false,
+            source_name: String::new(),
+            file_resolver: None,
+            defines: HashMap::new(),
+            case_sensitive: true,
+            target_isa: TargetIsa::Extended,
+            titokone: false,
+            lints: HashSet::from(Lint::ALL),
+            warnings_as_errors: false,
+        }
+    }
+}
+
+impl fmt::Debug for CompileOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CompileOptions")
+            .field("error_limit", &self.error_limit)
+            .field("debug_info", &self.debug_info)
+            .field("constants_section", &self.constants_section)
+            .field("source_name", &self.source_name)
+            .field("file_resolver", &self.file_resolver.as_ref().map(|_| "..."))
+            .field("defines", &self.defines)
+            .field("case_sensitive", &self.case_sensitive)
+            .field("target_isa", &self.target_isa)
+            .field("titokone", &self.titokone)
+            .field("lints", &self.lints)
+            .field("warnings_as_errors", &self.warnings_as_errors)
+            .finish()
+    }
+}
+
+/// Compile k91 source code into .b91 file contents with default options.
+/// On failure, returns every problem found in the source.
+pub fn compile(source: String) -> Result<String, Vec<Diagnostic>> {
+    compile_with_options(source, &CompileOptions::default())
+}
+
+/// Compile k91 source code into .b91 file contents. In Titokone mode, the layout is Titokone's.
+/// On failure, returns every problem found in the source, up to the error limit.
+pub fn compile_with_options(source: String, options: &CompileOptions) -> Result<String, Vec<Diagnostic>> {
+    compile_to_b91(source, options).map(|b91| match options.titokone {
+        true => b91.to_titokone_string(),
+        false => b91.to_string(),
+    })
+}
+
+/// Compile k91 source code into a [B91] struct, ready to be loaded without parsing.
+/// On failure, returns every problem found in the source, up to the error limit.
+pub fn compile_to_b91(source: String, options: &CompileOptions) -> Result<B91, Vec<Diagnostic>> {
+    assemble(&source, options).map(|assembly| assembly.b91)
+}
+
+/// Compile k91 source code, and get everything out of it: the [B91] struct, warnings, and the
+/// listing. On failure, returns every error found in the source, up to the error limit. If
+/// warnings are treated as errors, they're among them.
+pub fn compile_full(source: String, options: &CompileOptions) -> Result<CompileOutput, Vec<Diagnostic>> {
+    let assembly = assemble(&source, options)?;
+    let listing = create_listing(&assembly, &options.source_name);
+    Ok(CompileOutput { b91: assembly.b91, warnings: assembly.warnings, listing })
+}
+
+/// Everything [compile_full] produces.
+#[derive(Debug)]
+pub struct CompileOutput {
+    pub b91: B91,
+    /// Warnings from enabled lints, in source order.
+    pub warnings: Vec<Diagnostic>,
+    /// Human-readable listing. It has every source line next to the address and contents it was
+    /// compiled into, followed by the symbol table.
+    pub listing: String,
+}
+
+/// Result of a successful compile.
+struct Assembly {
+    b91: B91,
+    /// Source code with includes expanded.
+    lines: Vec<SourceLine>,
+    /// Absolute symbol table, with types.
+    symbol_table: HashMap<String, Symbol>,
+    /// What each statement was compiled into, and where it went.
+    placements: Vec<Placement>,
+    /// Warnings from enabled lints, in source order.
+    warnings: Vec<Diagnostic>,
+}
+
+/// A statement that takes up memory.
+struct Placement {
+    /// Position in the preprocessed source.
+    index: usize,
+    address: usize,
+    content: Vec<i32>,
+    is_code: bool,
+}
+
+fn assemble(source: &str, options: &CompileOptions) -> Result<Assembly, Vec<Diagnostic>> {
+    let mut diagnostics: Vec<Diagnostic> = Vec::new();
 
     // Start address. Zero if none.
     let mut org: Option<usize> = None;
 
-    // Dictionary of symbols
-    let mut symbol_table: HashMap<String, Symbol>;
-
     // These contain source processed into integers.
-    let data_segment: Vec<i32>;
     let mut code_segment: Vec<i32> = Vec::new();
+    let mut data_segment: Vec<i32> = Vec::new();
+
+    // Source comments and locations, by absolute address.
+    let mut comments: HashMap<usize, String> = HashMap::new();
+    let mut debug_info: HashMap<usize, SourceLocation> = HashMap::new();
+    let mut placements: Vec<Placement> = Vec::new();
+
+    // Source code with includes pulled in.
+    let lines = match preprocess(source, options) {
+        Ok(lines) => lines,
+        Err(errors) => return Err(apply_error_limit(errors, options)),
+    };
 
     // Source code distilled into "Statement" structs.
-    let mut statements;
-    match code_to_statements(&source) {
-        Ok(val) => statements = val,
-        Err(e) => return Err(e)
+    // Broken lines would only cause confusing errors later, so stop here if there are any.
+    let mut statements = match code_to_statements(&lines) {
+        Ok(statements) => statements,
+        Err(errors) => return Err(apply_error_limit(errors, options)),
+    };
+
+    // Case-insensitive symbols are all lowercase from here on.
+    if !options.symbols_case_sensitive() {
+        fold_symbol_case(&mut statements);
+    }
+    let defines = options.effective_defines();
+
+    // Local labels get their full names.
+    if let Err(errors) = qualify_local_labels(&mut statements) {
+        return Err(apply_error_limit(errors, options));
+    }
+    if let Err(errors) = resolve_numeric_labels(&mut statements) {
+        return Err(apply_error_limit(errors, options));
     }
 
     // Guard: Multiple definition
-    match assert_no_multiple_definition(&statements) {
-        Ok(()) => {}
-        Err(e) => return Err(e)
+    if let Err(mut errors) = assert_no_multiple_definition(&statements, &defines) {
+        diagnostics.append(&mut errors);
     }
 
     // Get directives
@@ -87,152 +399,252 @@ pub fn compile(source: String) -> Result<String, String> {
         if statement.statement_type != Keyword::Directive {
             continue;
         }
-        let keyword = statement.words[0].as_str();
-        match keyword {
+        let keyword = statement.words[0].text.to_uppercase();
+        match keyword.as_str() {
             "ORG" => {
-                if org != None {
-                    return Err(format!("Found 'ORG' on line {}, but it's already defined!", statement.line));
+                if org.is_some() {
+                    diagnostics.push(statement.error(ErrorCode::RepeatDirective, "Found 'ORG', but it's already defined!"));
+                    continue;
+                }
+                match parse_org_directive(statement) {
+                    Ok(value) => org = Some(value),
+                    Err(e) => diagnostics.push(e),
                 }
-                org = Some(parse_org_directive(statement)?);
             }
-            _ => return Err(format!("Compiler made an error on line {}: {} is not a directive.", statement.line, keyword))
+            // Already handled by the preprocessor
+            "INCLUDE" => (),
+            _ => diagnostics.push(statement.error(ErrorCode::Internal, format!("Compiler made an error: {} is not a directive.", keyword)))
         }
     }
     // Unpack org from option.
     let org = org.unwrap_or(0);
 
-    // Create symbol table
-    match create_symbol_table(&statements) {
-        Ok(result) => symbol_table = result,
-        Err(e) => return Err(e)
-    }
-
-    // Apply offsets to symbol table
+    // Segment locations
     let code_size = get_code_segment_size(&statements);
-    symbol_table = create_absolute_symbol_table(symbol_table, org + 0, org + code_size);
+    let data_start = org + code_size;
+
+    // Create symbol table. Without it, we can't go any further.
+    let symbol_table = match create_symbol_table(&statements, &defines, org, data_start) {
+        Ok(result) => result,
+        Err(mut errors) => {
+            diagnostics.append(&mut errors);
+            return Err(apply_error_limit(diagnostics, options));
+        }
+    };
 
+    // Apply offsets to symbol table
+    let symbol_table = create_absolute_symbol_table(symbol_table, org, data_start);
 
     // Get Data Segment
-    match parse_data_statements(&mut statements) {
-        Ok(segment) => data_segment = segment,
-        Err(e) => return Err(e)
+    for statement in &statements {
+        if statement.statement_type == Keyword::Data {
+            match parse_data_statement(statement, &symbol_table) {
+                Ok(mut data) => {
+                    let address = data_start + data_segment.len();
+                    if let Some(comment) = statement.comment_text() {
+                        comments.insert(address, comment);
+                    }
+                    if options.debug_info {
+                        debug_info.insert(address, statement.location());
+                    }
+                    placements.push(Placement { index: statement.index, address, content: data.clone(), is_code: false });
+                    data_segment.append(&mut data);
+                }
+                Err(e) => diagnostics.push(e),
+            }
+        }
     }
 
     // Get Code Segment
-    for statement in statements {
+    for statement in &statements {
         if statement.statement_type == Keyword::Code {
-            code_segment.push(parse_instruction(statement, &symbol_table)?);
+            let address = org + code_segment.len();
+            if let Some(comment) = statement.comment_text() {
+                comments.insert(address, comment);
+            }
+            if options.debug_info {
+                debug_info.insert(address, statement.location());
+            }
+
+            // Guard: Instruction set
+            if options.effective_target_isa() == TargetIsa::Classic {
+                if let Err(e) = assert_classic_isa(statement, options.titokone) {
+                    diagnostics.push(e);
+                    continue;
+                }
+            }
+
+            match parse_instruction(statement, &symbol_table) {
+                Ok(instruction) => {
+                    placements.push(Placement { index: statement.index, address, content: vec![instruction], is_code: true });
+                    code_segment.push(instruction);
+                }
+                Err(mut errors) => diagnostics.append(&mut errors),
+            }
         }
     }
 
+    if !diagnostics.is_empty() {
+        return Err(apply_error_limit(diagnostics, options));
+    }
+
+    // Lints
+    let warnings = run_lints(&statements, &symbol_table, &options.lints);
+    if options.warnings_as_errors && !warnings.is_empty() {
+        let errors = warnings.into_iter()
+            .map(|warning| Diagnostic { severity: Severity::Error, ..warning })
+            .collect();
+        return Err(apply_error_limit(errors, options));
+    }
+
     // Mash them together
-    let binary;
-    match build_b91(
+    let mut b91 = build_b91(
         code_segment,
         data_segment,
-        symbol_table,
+        &symbol_table,
+        comments,
+        debug_info,
         org,
-    ) {
-        Ok(result) => binary = result,
-        Err(e) => return Err(e)
+    );
+    if options.titokone {
+        b91.symbol_table.extend(used_builtins(&statements));
+    }
+    if options.constants_section {
+        // Builtins aren't in the symbol table, but they're constants too.
+        b91.constants = b91.symbol_table.keys()
+            .filter(|name| symbol_table.get(*name).is_none_or(|symbol| symbol.symbol_type == SymbolType::Const))
+            .cloned()
+            .collect();
+    }
+    Ok(Assembly {
+        b91,
+        lines,
+        symbol_table,
+        placements,
+        warnings,
+    })
+}
+
+/// Cut the list of errors down to the limit, and say so if anything was cut.
+fn apply_error_limit(mut diagnostics: Vec<Diagnostic>, options: &CompileOptions) -> Vec<Diagnostic> {
+    if let Some(limit) = options.error_limit {
+        if diagnostics.len() > limit {
+            let omitted = diagnostics.len() - limit;
+            diagnostics.truncate(limit);
+            diagnostics.push(Diagnostic::error(ErrorCode::TooManyErrors, 0, format!("Too many errors, {} more not shown.", omitted)));
+        }
     }
-    Ok(binary)
+    diagnostics
 }
 
 /// This will find all relevant source code lines, and break them into "Statements"
-fn code_to_statements(source: &String) -> Result<Vec<Statement>, String> {
+/// Lines that can't be made into a statement are skipped, and reported at the end.
+fn code_to_statements(lines: &[SourceLine]) -> Result<Vec<Statement>, Vec<Diagnostic>> {
     let mut statements: Vec<Statement> = Vec::new();
+    let mut errors: Vec<Diagnostic> = Vec::new();
 
-    for (i, text) in source.lines().enumerate() {
-        let mut text = text.to_owned();
-
-        let statement_type: Keyword;
-        let line = i + 1;
-        let label: Option<String>;
-        let comment: Option<String>;
-
-        // Get comment and remove it from the text line
-        match text.split_once(';') {
-            Some((before, after)) => {
-                comment = Some(after.to_string());
-                text = before.to_owned();
-            }
-            None => comment = None,
+    for (index, source_line) in lines.iter().enumerate() {
+        let line = source_line.line;
+        let file = source_line.file.as_str();
+        if source_line.skip {
+            continue;
         }
 
-        // Split the text line into words
-        text = text.replace(",", " ");
-        let mut words: Vec<String> = text.split_whitespace().map(str::to_string).collect();
+        // Split the text line into words and comment
+        let tokenized = tokenize_line(&source_line.text);
+        let comment = tokenized.comment;
+        let mut words = tokenized.words;
         if words.is_empty() {
             continue;
         }
 
+        // Statement location on the line
+        let columns = words[0].columns.start..words[words.len() - 1].columns.end;
+
         // Get label and remove it from keywords
-        if str_to_keyword_type(&words[0]) == Keyword::None {
-            label = Some(words[0].to_owned());
-            words.remove(0);
+        let label = if str_to_keyword_type(&words[0].text) == Keyword::None {
+            Some(words.remove(0))
         } else {
-            label = None
-        }
-        if words.is_empty() {
-            return Err(format!("Unexpected end, {}\n{}", line, text));
-        }
+            None
+        };
+        let Some(first) = words.first() else {
+            let label = label.unwrap();
+            errors.push(Diagnostic::error(ErrorCode::MissingKeyword, line, format!("Unexpected end after label '{}'", label.text))
+                .with_file(file)
+                .with_columns(label.columns)
+                .with_help("A label must be followed by an instruction or a variable on the same line."));
+            continue;
+        };
 
         // Find the statement's type by looking at the first word.
-        let keyword_string = words[0].to_uppercase();
-        let keyword = keyword_string.as_str();
-        match str_to_keyword_type(keyword) {
+        let keyword = first.text.to_uppercase();
+        let statement_type = match str_to_keyword_type(&keyword) {
             Keyword::None => {
-                return Err(format!("Unknown keyword '{}' on line {}\n{}", keyword, line, text));
+                errors.push(Diagnostic::error(ErrorCode::UnknownKeyword, line, format!("Unknown keyword '{}'", keyword))
+                    .with_file(file)
+                    .with_columns(first.columns.clone())
+                    .with_token(first.text.as_str()));
+                continue;
             }
             Keyword::Register => {
-                return Err(format!("Unexpected register '{}' on line {}\n{}", keyword, line, text));
+                errors.push(Diagnostic::error(ErrorCode::UnexpectedRegister, line, format!("Unexpected register '{}'", keyword))
+                    .with_file(file)
+                    .with_columns(first.columns.clone())
+                    .with_token(first.text.as_str()));
+                continue;
             }
-            Keyword::Directive => statement_type = Keyword::Directive,
-            Keyword::Data => statement_type = Keyword::Data,
-            Keyword::Const => statement_type = Keyword::Const,
-            Keyword::Code => statement_type = Keyword::Code,
-        }
+            Keyword::Directive => Keyword::Directive,
+            Keyword::Data => Keyword::Data,
+            Keyword::Const => Keyword::Const,
+            Keyword::Code => Keyword::Code,
+        };
 
         // Make a statement.
         statements.push(Statement {
             statement_type,
             words,
-            line: i + 1,
+            file: file.to_string(),
+            line,
+            index,
+            columns,
             label,
             comment,
+            macro_calls: source_line.macro_calls.clone(),
+            scope: None,
+            numeric_refs: HashMap::new(),
+            fold_case: false,
         })
     }
-    return Ok(statements);
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    Ok(statements)
 }
 
-fn parse_org_directive(statement: &Statement) -> Result<usize, String> {
-    let value;
-    let keyword_string = statement.words[0].to_uppercase();
-    let keyword = keyword_string.as_str();
-    let line = statement.line;
+fn parse_org_directive(statement: &Statement) -> Result<usize, Diagnostic> {
+    let keyword = statement.words[0].text.to_uppercase();
 
     // Guard: Label
-    if !statement.label.is_none() {
-        return Err(format!("You can't label a compiler directive! '{}' on line {}", keyword, line));
+    if statement.label.is_some() {
+        return Err(statement.error(ErrorCode::LabeledDirective, format!("You can't label a compiler directive! '{}'", keyword)));
     }
 
     // Guard: Incorrect number of words
     match statement.words.len() {
         2 => (), // expected amount
-        1 => return Err(format!("No value given for '{}' on line {}", keyword, line)),
-        _ => return Err(format!("Too many words for '{}' on line {}", keyword, line)),
+        1 => return Err(statement.error(ErrorCode::MissingValue, format!("No value given for '{}'", keyword))),
+        _ => return Err(statement.error(ErrorCode::TooManyValues, format!("Too many words for '{}'", keyword))),
     }
 
-    // Get value
-    match str_to_integer(&statement.words[1]) {
-        Ok(val) => value = val,
-        Err(e) => return Err(format!("Can't parse value on line {}: {}", line, e))
-    }
+    // Get value. Nothing is defined yet, so only builtins are available.
+    let value = statement.eval_word(&statement.words[1], |name| str_to_builtin_const(name).ok())?;
 
     // Guard: Value out of range
     if value < 0 {
-        return Err(format!("You tried to offset the program to a negative address! '{}' on line {}", keyword, line));
+        return Err(statement.error_at(&statement.words[1], ErrorCode::OutOfRange, format!("You tried to offset the program to a negative address! '{}'", keyword)));
+    }
+    if value > u16::MAX as i64 {
+        return Err(statement.error_at(&statement.words[1], ErrorCode::OutOfRange, format!("Program start is out of address range! '{}'", keyword)));
     }
 
     // Ok.
@@ -240,40 +652,55 @@ fn parse_org_directive(statement: &Statement) -> Result<usize, String> {
 }
 
 
-fn create_symbol_table(statements: &Vec<Statement>) -> Result<HashMap<String, Symbol>, String> {
-    let mut map = HashMap::new();
-    let mut code_offset = -1;
-    let mut data_offset = -1;
-    for statement in statements {
-        match statement.statement_type {
-            Keyword::Const => if statement.label.is_none() {
-                return Err(format!("Line {}: Constant requires a name!", statement.line));
-            }
-            Keyword::Code => code_offset += 1,
-            Keyword::Data => data_offset += 1,
+/// Find the value of every symbol. Code and data labels get offsets relative to the start of their
+/// segment, constants get their value. Constants may refer to each other and to labels in any
+/// order, as long as they don't go in circles. Defines are constants too.
+fn create_symbol_table(statements: &[Statement], defines: &HashMap<String, i32>, code_start: usize, data_start: usize) -> Result<HashMap<String, Symbol>, Vec<Diagnostic>> {
+    let mut map: HashMap<String, Symbol> = defines.iter()
+        .map(|(name, value)| (name.clone(), Symbol { offset: *value, symbol_type: SymbolType::Const }))
+        .collect();
+    let mut errors = Vec::new();
+    let mut resolver = SymbolResolver::new(statements, defines, code_start, data_start);
+    for (index, statement) in statements.iter().enumerate() {
+        let symbol_type = match statement.statement_type {
+            Keyword::Const => SymbolType::Const,
+            Keyword::Code => SymbolType::Code,
+            Keyword::Data => SymbolType::Data,
             _ => continue
-        }
+        };
 
-        // Add symbol
-        if let Some(label) = &statement.label {
-            let symbol;
-            match &statement.statement_type {
-                Keyword::Const => symbol = Symbol { offset: parse_const(statement)?, symbol_type: SymbolType::Const },
-                Keyword::Code => symbol = Symbol { offset: code_offset, symbol_type: SymbolType::Code },
-                Keyword::Data => symbol = Symbol { offset: data_offset, symbol_type: SymbolType::Data },
-                _ => continue
-            }
-            map.insert(label.clone(), symbol);
+        // Guard: Nameless constant
+        if symbol_type == SymbolType::Const && statement.label.is_none() {
+            errors.push(statement.error(ErrorCode::UnnamedConstant, "Constant requires a name!"));
+            continue;
         }
 
-        // Data segment: Compensate for remaining size.
-        if statement.words[0].to_uppercase().as_str() == "DS" {
+        // Data segment: Check size, even if there's no label.
+        if statement.words[0].text.to_uppercase().as_str() == "DS" {
             if statement.words.len() < 2 {
-                return Err(format!("Line {}: No size for data segment!", statement.line));
+                errors.push(statement.error(ErrorCode::MissingValue, "No size for data segment!"));
+                continue;
             }
-            // -1 because we already incremented offset
-            data_offset += str_to_integer(statement.words[1].as_str())? - 1
+            resolver.data_size(index);
         }
+
+        // Add symbol
+        if let Some(label) = &statement.label {
+            // Broken symbols report their own errors.
+            let Some(value) = resolver.resolve(&label.text) else {
+                continue;
+            };
+            let offset = match symbol_type {
+                SymbolType::Const => value,
+                SymbolType::Code => value - code_start as i32,
+                SymbolType::Data => value - data_start as i32,
+            };
+            map.insert(label.text.clone(), Symbol { offset, symbol_type });
+        }
+    }
+    errors.append(&mut resolver.errors);
+    if !errors.is_empty() {
+        return Err(errors);
     }
     Ok(map)
 }
@@ -281,7 +708,7 @@ fn create_symbol_table(statements: &Vec<Statement>) -> Result<HashMap<String, Sy
 /// Apply relevant segment offsets to values.
 fn create_absolute_symbol_table(relative_table: HashMap<String, Symbol>, code_start: usize, data_start: usize) -> HashMap<String, Symbol> {
     let mut absolute_table = HashMap::new();
-    for (label, mut value) in &mut relative_table.into_iter() {
+    for (label, mut value) in relative_table.into_iter() {
         match value.symbol_type {
             SymbolType::Const => {}
             SymbolType::Code => value.offset += code_start as i32,
@@ -292,82 +719,124 @@ fn create_absolute_symbol_table(relative_table: HashMap<String, Symbol>, code_st
     absolute_table
 }
 
-fn parse_const(statement: &Statement) -> Result<i32, String> {
-    let keyword_string = statement.words[0].to_uppercase();
-    let keyword = keyword_string.as_str();
-    let line = statement.line;
-    let value;
+/// Get the value of an EQU. Symbols it refers to are looked up with `lookup`.
+fn parse_const(statement: &Statement, lookup: impl Fn(&str) -> Option<i32>) -> Result<i32, Diagnostic> {
+    let keyword = statement.words[0].text.to_uppercase();
 
     match statement.words.len() {
         2 => (), // expected amount
-        1 => return Err(format!("Line {}: No value given for '{}'", line, keyword)),
-        _ => return Err(format!("Line {}: Too many words for '{}'", line, keyword)),
+        1 => return Err(statement.error(ErrorCode::MissingValue, format!("No value given for '{}'", keyword))),
+        _ => return Err(statement.error(ErrorCode::TooManyValues, format!("Too many words for '{}'", keyword))),
     }
 
-    match str_to_integer(&statement.words[1]) {
-        Ok(val) => value = val,
-        Err(e) => return Err(format!("Line {}: Error parsing value: {}", e, line))
-    }
+    let value = statement.eval_word(&statement.words[1], lookup)?;
 
-    if value < i16::MIN as i32 || value > i16::MAX as i32 {
-        return Err(format!("Line {}: Value out of range. Note that constants are 16-bit only.", line));
+    if value < i16::MIN as i64 || value > i16::MAX as i64 {
+        return Err(statement.error_at(&statement.words[1], ErrorCode::OutOfRange, "Value out of range. Note that constants are 16-bit only."));
     }
-    Ok(value)
+    Ok(value as i32)
 }
 
 
-/// Creates data segment and data symbols
-fn parse_data_statements(
-    statements: &mut Vec<Statement>)
-    -> Result<Vec<i32>, String>
-{
-    let mut data_segment = Vec::new();
+/// Get the contents of a single data statement:
+/// - `DC a, b, c` stores each value.
+/// - `DS n` allocates n addresses, and `DS n, value` fills them with value.
+///
+/// Values must be separated by commas.
+/// - `STRING "text"` stores one character code per address, and `STRINGZ` adds a zero after it.
+fn parse_data_statement(statement: &Statement, symbol_table: &HashMap<String, Symbol>) -> Result<Vec<i32>, Diagnostic> {
+    let keyword = statement.words[0].text.to_uppercase();
+    let lookup = |name: &str| str_to_builtin_const(name).ok()
+        .or_else(|| symbol_table.get(name).map(|symbol| symbol.offset));
+
+    // Guard: Missing comma
+    if matches!(keyword.as_str(), "DC" | "DS") {
+        if let Some(word) = statement.words.iter().skip(2).find(|word| !word.after_comma) {
+            return Err(statement.error_at(word, ErrorCode::TooManyValues, format!("Expected ',' before '{}'", word.text))
+                .with_help(format!("Separate the values of '{}' with commas.", keyword)));
+        }
+    }
 
-    for statement in statements {
-        if statement.statement_type != Keyword::Data {
-            continue;
+    match keyword.as_str() {
+        // Data Constant - store values.
+        "DC" => {
+            // Guard: No values
+            if statement.words.len() < 2 {
+                return Err(statement.error(ErrorCode::MissingValue, format!("No value given for '{}'", keyword)));
+            }
+            statement.words[1..].iter()
+                .map(|word| parse_data_word(statement, word, lookup))
+                .collect()
         }
 
-        let keyword_string = statement.words[0].to_uppercase();
-        let keyword = keyword_string.as_str();
-        let line = statement.line;
-        let value;
+        // Data Segment - allocate space, and optionally fill it.
+        "DS" => {
+            // Guard: Word count
+            match statement.words.len() {
+                2 | 3 => (), // size, and maybe fill value
+                1 => return Err(statement.error(ErrorCode::MissingValue, format!("No value given for '{}'", keyword))),
+                _ => return Err(statement.error_at(&statement.words[3], ErrorCode::TooManyValues, format!("Too many words for '{}'", keyword))),
+            }
+
+            let value = statement.eval_word(&statement.words[1], lookup)?;
 
-        // Guard: Word count
-        match statement.words.len() {
-            2 => (), // expected amount
-            1 => return Err(format!("No value given for '{}' on line {}", keyword, line)),
-            _ => return Err(format!("Too many words for '{}' on line {}", keyword, line)),
-        }
+            // Guard: out of range
+            if value < 0 {
+                return Err(statement.error_at(&statement.words[1], ErrorCode::OutOfRange, format!("You tried to allocate a negative number of addresses! '{}'", keyword)));
+            } else if value == 0 {
+                return Err(statement.error_at(&statement.words[1], ErrorCode::OutOfRange, format!("You tried to allocate a zero addresses! '{}'", keyword)));
+            } else if value > u16::MAX as i64 {
+                return Err(statement.error_at(&statement.words[1], ErrorCode::OutOfRange, format!("You tried to allocate more than the address space! '{}'", keyword)));
+            }
 
-        // Get value
-        match str_to_integer(&statement.words[1]) {
-            Ok(val) => value = val,
-            Err(e) => return Err(format!("Error parsing value on line {}: {}", line, e))
+            let fill = match statement.words.get(2) {
+                Some(word) => parse_data_word(statement, word, lookup)?,
+                None => 0,
+            };
+            Ok(vec![fill; value as usize])
         }
 
-        match keyword {
-            // Data Constant - store a value
-            "DC" => data_segment.push(value),
+        // Strings
+        "STRING" | "STRINGZ" => parse_string_data(statement),
 
-            // Data Segment - allocate space
-            "DS" => {
-                // Guard: out of range
-                if value < 0 {
-                    return Err(format!("You tried to allocate a negative number of addresses! '{}' on line {}", keyword, line));
-                } else if value == 0 {
-                    return Err(format!("You tried to allocate a zero addresses! '{}' on line {}", keyword, line));
-                }
+        _ => Err(statement.error(ErrorCode::Internal, format!("Error: '{}' is not a variable keyword. This is compiler's fault, not yours. Please file an issue.", keyword))),
+    }
+}
 
-                // Push data
-                for _ in 0..value {
-                    data_segment.push(0);
-                }
-            }
-            _ => return Err(format!("Error: '{}' on line {} is not a variable keyword. This is compiler's fault, not yours. Please file an issue.", keyword, line)),
-        }
+/// Get a value to be stored in memory. Anything that fits in 32 bits, signed or not.
+fn parse_data_word(statement: &Statement, word: &Token, lookup: impl Fn(&str) -> Option<i32>) -> Result<i32, Diagnostic> {
+    let keyword = statement.words[0].text.to_uppercase();
+    let value = statement.eval_word(word, lookup)?;
+    if value < i32::MIN as i64 || value > u32::MAX as i64 {
+        return Err(statement.error_at(word, ErrorCode::OutOfRange, format!("Value {} doesn't fit in a word! '{}'", value, keyword)));
+    }
+    Ok(value as i32)
+}
+
+/// Get the character codes of a STRING or STRINGZ. STRINGZ ends with a zero.
+fn parse_string_data(statement: &Statement) -> Result<Vec<i32>, Diagnostic> {
+    let keyword = statement.words[0].text.to_uppercase();
+
+    // Guard: Word count
+    match statement.words.len() {
+        2 => (), // expected amount
+        1 => return Err(statement.error(ErrorCode::MissingValue, format!("No string given for '{}'", keyword))),
+        _ => return Err(statement.error_at(&statement.words[2], ErrorCode::TooManyValues, format!("Too many words for '{}'", keyword))),
+    }
+
+    let text = parse_string_literal(&statement.words[1].text)
+        .map_err(|e| statement.error_at(&statement.words[1], ErrorCode::InvalidString, e))?;
+    let mut data: Vec<i32> = text.chars().map(|c| c as i32).collect();
+    if keyword == "STRINGZ" {
+        data.push(0);
+    }
+
+    // Guard: Nothing to store
+    if data.is_empty() {
+        return Err(statement.error_at(&statement.words[1], ErrorCode::InvalidString, format!("Empty string takes no space! '{}'", keyword))
+            .with_help("Use STRINGZ to store just the terminating zero."));
     }
-    Ok(data_segment)
+    Ok(data)
 }
 
 fn get_code_segment_size(statements: &Vec<Statement>) -> usize {
@@ -381,34 +850,174 @@ fn get_code_segment_size(statements: &Vec<Statement>) -> usize {
     code_offset
 }
 
-/// Go through statements and check if same label comes up more than once.
-fn assert_no_multiple_definition(statements: &Vec<Statement>) -> Result<(), String> {
-    let mut failed = false;
-    let mut definitions: HashMap<String, Vec<usize>> = HashMap::new();
-    // Collect all definitions
-    for statement in statements {
-        if let Some(label) = &statement.label {
-            if definitions.contains_key(label) {
-                // Defined already! mark failed add this line to the entry.
-                failed = true;
-                definitions.get_mut(label).unwrap().push(statement.line);
+/// Give local labels (".loop") their full names ("main.loop"), and give every statement the scope
+/// it's in. Scope is the most recent global label. Constants and labels made by macros don't start
+/// a new scope, so they can go between a label and its local labels.
+fn qualify_local_labels(statements: &mut [Statement]) -> Result<(), Vec<Diagnostic>> {
+    let mut errors = Vec::new();
+    let mut scope: Option<String> = None;
+    for statement in statements.iter_mut() {
+        if let Some(label) = statement.label.clone() {
+            if is_numeric_label(&label.text) {
+                // Numeric labels are handled separately, and don't start a scope.
+            } else if !label.text.starts_with('.') {
+                if statement.statement_type != Keyword::Const && !label.text.contains('#') {
+                    scope = Some(label.text);
+                }
+            } else if label.text.len() == 1 {
+                errors.push(statement.error_at(&label, ErrorCode::LocalLabel, "Local label needs a name after '.'"));
+            } else if let Some(scope) = &scope {
+                statement.label.as_mut().unwrap().text = format!("{}{}", scope, label.text);
             } else {
-                // First definition: create an entry that contains this line.
-                let mut vec: Vec<usize> = Vec::new();
-                vec.push(statement.line);
-                definitions.insert(label.clone(), vec);
+                errors.push(statement.error_at(&label, ErrorCode::LocalLabel, format!("Local label '{}' has no global label before it", label.text))
+                    .with_help("Local labels belong to the label above them. Add a label without '.' somewhere before this."));
             }
         }
+        statement.scope = scope.clone();
+    }
+    if !errors.is_empty() {
+        return Err(errors);
     }
-    // Failure: Construct an error message
-    if failed {
-        let mut err_mgs = "Multiple definitions:".to_string();
-        for (label, lines) in definitions {
-            if lines.len() > 1 {
-                err_mgs += format!("\n    {} on lines: {:?}", label, lines).as_str()
+    Ok(())
+}
+
+/// Make symbols case-insensitive: labels are folded to lowercase, and so are names used in the
+/// statements when they're looked up.
+fn fold_symbol_case(statements: &mut [Statement]) {
+    for statement in statements {
+        statement.fold_case = true;
+        if let Some(label) = statement.label.as_mut() {
+            label.text = label.text.to_lowercase();
+        }
+    }
+}
+
+/// Builtin constants the program uses, by lowercase name. Titokone lists them in the symbol table.
+fn used_builtins(statements: &[Statement]) -> HashMap<String, i32> {
+    let mut builtins = HashMap::new();
+    for statement in statements {
+        for word in statement.words.iter().skip(1) {
+            for name in word_names(&word.text) {
+                if let Ok(value) = str_to_builtin_const(&statement.qualify(name)) {
+                    builtins.insert(name.to_lowercase(), value);
+                }
             }
         }
-        return Err(err_mgs);
+    }
+    builtins
+}
+
+/// Names that appear in a word: runs of letters, digits, '_' and '.'.
+fn word_names(text: &str) -> impl Iterator<Item = &str> {
+    text.split(|c: char| !(c.is_alphanumeric() || c == '_' || c == '.'))
+        .filter(|name| !name.is_empty())
+}
+
+/// Check that an instruction is in the classic TTK-91 instruction set.
+fn assert_classic_isa(statement: &Statement, titokone: bool) -> Result<(), Diagnostic> {
+    let keyword = &statement.words[0];
+    let help = match titokone {
+        true => "Titokone only knows the classic TTK-91 instructions.",
+        false => "Target instruction set is classic. Target the extended instruction set to use this.",
+    };
+    match OpCode::from_str(&keyword.text.to_uppercase()) {
+        Ok(opcode) if !opcode.is_classic_isa() => Err(statement.error_at(keyword, ErrorCode::UnsupportedInstruction, format!("'{}' is not in the classic instruction set", keyword.text.to_uppercase()))
+            .with_help(help)),
+        _ => Ok(()),
+    }
+}
+
+/// Is this a numeric label definition, like "1:"?
+fn is_numeric_label(label: &str) -> bool {
+    label.strip_suffix(':').is_some_and(|number| !number.is_empty() && number.chars().all(|c| c.is_ascii_digit()))
+}
+
+/// Is this a numeric label reference, like "1b" or "1f"? Uppercase "1B" and "1F" work too.
+pub(crate) fn is_numeric_reference(name: &str) -> bool {
+    name.strip_suffix(['b', 'f', 'B', 'F']).is_some_and(|number| !number.is_empty() && number.chars().all(|c| c.is_ascii_digit()))
+}
+
+/// Give numeric labels ("1:") unique names, and point every reference to one ("1b", "1f") at the
+/// nearest definition before or after it. "1b" can refer to a label on the same statement, "1f"
+/// can't. The n:th definition of "1:" is named "_1_n", which is what shows up in the symbol table.
+fn resolve_numeric_labels(statements: &mut [Statement]) -> Result<(), Vec<Diagnostic>> {
+    let mut errors = Vec::new();
+
+    // Label number -> (statement index, unique name) for every definition, in order.
+    let mut definitions: HashMap<String, Vec<(usize, String)>> = HashMap::new();
+    for (index, statement) in statements.iter_mut().enumerate() {
+        let Some(label) = statement.label.as_mut() else {
+            continue;
+        };
+        if !is_numeric_label(&label.text) {
+            continue;
+        }
+        let number = label.text.trim_end_matches(':').to_string();
+        let numbered = definitions.entry(number.clone()).or_default();
+        label.text = format!("_{}_{}", number, numbered.len() + 1);
+        numbered.push((index, label.text.clone()));
+    }
+
+    // Find what each reference points to.
+    for (index, statement) in statements.iter_mut().enumerate() {
+        let mut numeric_refs = HashMap::new();
+        for word in statement.words.iter().skip(1) {
+            let references = word_names(&word.text).filter(|name| is_numeric_reference(name));
+            for name in references {
+                let (number, direction) = name.split_at(name.len() - 1);
+                let numbered = definitions.get(number).map(Vec::as_slice).unwrap_or_default();
+                let backward = direction.eq_ignore_ascii_case("b");
+                let target = match backward {
+                    true => numbered.iter().rev().find(|(i, _)| *i <= index),
+                    false => numbered.iter().find(|(i, _)| *i > index),
+                };
+                match target {
+                    Some((_, target)) => {
+                        numeric_refs.insert(name.to_string(), target.clone());
+                    }
+                    None => {
+                        let side = if backward { "before" } else { "after" };
+                        errors.push(statement.error_at(word, ErrorCode::LocalLabel, format!("No label '{}:' {} '{}'", number, side, name)));
+                    }
+                }
+            }
+        }
+        statement.numeric_refs = numeric_refs;
+    }
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    Ok(())
+}
+
+/// Go through statements and check if same label comes up more than once.
+/// Every repeat definition gets its own error. Labels can't reuse the name of a define, either.
+fn assert_no_multiple_definition(statements: &Vec<Statement>, defines: &HashMap<String, i32>) -> Result<(), Vec<Diagnostic>> {
+    let mut errors = Vec::new();
+    let mut definitions: HashMap<&String, usize> = HashMap::new();
+    for statement in statements {
+        if let Some(label) = &statement.label {
+            if defines.contains_key(&label.text) {
+                errors.push(statement.error_at(label, ErrorCode::MultipleDefinition, format!("Multiple definitions: '{}'", label.text))
+                    .with_help("It's already given as a define. Use IFNDEF to give it a default value."));
+                continue;
+            }
+            match definitions.get(&label.text) {
+                // Defined already!
+                Some(first_line) => errors.push(
+                    statement.error_at(label, ErrorCode::MultipleDefinition, format!("Multiple definitions: '{}'", label.text))
+                        .with_help(format!("First defined on line {}", first_line))
+                ),
+                // First definition
+                None => {
+                    definitions.insert(&label.text, statement.line);
+                }
+            }
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
     }
     // Success
     Ok(())
@@ -417,64 +1026,61 @@ fn assert_no_multiple_definition(statements: &Vec<Statement>) -> Result<(), Stri
 fn build_b91(
     code_segment: Vec<i32>,
     data_segment: Vec<i32>,
-    symbol_table: HashMap<String, Symbol>,
+    symbol_table: &HashMap<String, Symbol>,
+    comments: HashMap<usize, String>,
+    debug_info: HashMap<usize, SourceLocation>,
     org: usize,
-) -> Result<String, String>
+) -> B91
 {
     let code_size = code_segment.len();
     let fp_start: i32 = (org + code_size) as i32 - 1; // fp_start can be -1 if code_size == 0
     let data_start = code_size + org;
     let sp_start = fp_start + data_segment.len() as i32;
 
-    let mut return_str = "___b91___\n".to_string();
-
-    // --- Code segment
-    return_str += "___code___\n";
-    // Code start and FP
-    return_str += format!("{} {}\n", org.to_string(), fp_start.to_string()).as_str();
-    // Actual code
-    for i in code_segment {
-        return_str += format!("{}\n", i.to_string()).as_str();
-    }
-
-    // --- Data segment
-    return_str += "___data___\n";
-    // Data start and SP
-    return_str += format!("{} {}\n", data_start.to_string(), sp_start.to_string()).as_str();
-    // Actual data
-    for i in data_segment {
-        return_str += format!("{}\n", i.to_string()).as_str();
-    }
-
-    // --- Symbol table
-    return_str += "___symboltable___\n";
-    for (label, value) in symbol_table.into_iter() {
-        return_str += format!("{} {}\n", label, value.offset).as_str();
+    B91 {
+        // Code start and FP
+        code_segment: B91Segment {
+            start: org as i32,
+            end: fp_start,
+            content: code_segment,
+        },
+        // Data start and SP
+        data_segment: B91Segment {
+            start: data_start as i32,
+            end: sp_start,
+            content: data_segment,
+        },
+        symbol_table: symbol_table.iter()
+            .map(|(label, value)| (label.clone(), value.offset))
+            .collect(),
+        comments,
+        debug_info,
+        ..Default::default()
     }
+}
 
-    // --- End
-    return_str += "___end___\n";
-
-    Ok(return_str)
+/// Names that can't be labels: instructions, registers, directives, and builtin constants, in any case.
+pub(crate) fn is_reserved_name(name: &str) -> bool {
+    str_to_keyword_type(name) != Keyword::None || str_to_builtin_const(&name.to_uppercase()).is_ok()
 }
 
 fn str_to_keyword_type(keyword: &str) -> Keyword {
-    let keyword_string = keyword.to_uppercase();
-    let keyword = keyword_string.as_str();
+    let keyword = keyword.to_uppercase();
+    let keyword = keyword.as_str();
 
-    if let Ok(_) = OpCode::from_str(keyword) {
+    if OpCode::from_str(keyword).is_ok() {
         return Keyword::Code;
     }
-    if let Ok(_) = Register::from_str(keyword) {
+    if Register::from_str(keyword).is_ok() {
         return Keyword::Register;
     }
     if keyword == "EQU" {
         return Keyword::Const;
     }
-    if keyword == "DS" || keyword == "DC" {
+    if matches!(keyword, "DS" | "DC" | "STRING" | "STRINGZ") {
         return Keyword::Data;
     }
-    if keyword == "ORG" {
+    if matches!(keyword, "ORG" | "INCLUDE" | "MACRO" | "ENDM") || is_conditional(keyword) {
         return Keyword::Directive;
     }
     Keyword::None
@@ -508,11 +1114,11 @@ fn str_to_integer(input_string: &str) -> Result<i32, String> {
     // Catch minus sign
     let minus = input_string.starts_with('-');
     if minus {
-        num_string = input_string.chars().into_iter().skip(1).collect();
+        num_string = input_string.chars().skip(1).collect();
     }
 
     // Catch Bin/Oct/Hex prefix
-    let prefix: String = num_string.chars().into_iter().take(2).collect();
+    let prefix: String = num_string.chars().take(2).collect();
     let radix = match prefix.as_str() {
         "0b" => 2,
         "0o" => 8,
@@ -524,11 +1130,10 @@ fn str_to_integer(input_string: &str) -> Result<i32, String> {
     }
 
     // u32 and then cast to i32 because from_str_radix doesn't seem to understand two's complement
-    let value: i32;
-    match u32::from_str_radix(num_string.as_str(), radix) {
-        Ok(int) => value = int as i32,
+    let value = match u32::from_str_radix(num_string.as_str(), radix) {
+        Ok(int) => int as i32,
         Err(e) => return Err(format!("{}: '{}'", e, input_string)),
-    }
+    };
 
     match minus {
         true => Ok(-value),
@@ -539,6 +1144,8 @@ fn str_to_integer(input_string: &str) -> Result<i32, String> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::compiler::preprocessor::split_lines;
+    use crate::disassembler::disassemble_instruction;
 
     #[test]
     fn test_parse_number() {
@@ -582,22 +1189,10 @@ mod tests {
 
     #[test]
     fn test_parse_org_directive() {
-        let statement = Statement {
-            statement_type: Keyword::Directive,
-            label: None,
-            words: "ORG 50".split_whitespace().map(str::to_string).collect(),
-            line: 0,
-            comment: None,
-        };
+        let statement = code_to_statements(&split_lines("ORG 50", "")).unwrap().remove(0);
         assert_eq!(parse_org_directive(&statement).unwrap(), 50);
 
-        let statement = Statement {
-            statement_type: Keyword::Directive,
-            label: None,
-            words: "ORG 0x1000".split_whitespace().map(str::to_string).collect(),
-            line: 0,
-            comment: None,
-        };
+        let statement = code_to_statements(&split_lines("ORG 0x1000", "")).unwrap().remove(0);
         assert_eq!(parse_org_directive(&statement).unwrap(), 0x1000);
     }
 
@@ -616,7 +1211,7 @@ mod tests {
         load r1, =2
         ;
         ;".to_string();
-        let statements = code_to_statements(&source).unwrap();
+        let statements = code_to_statements(&split_lines(&source, "")).unwrap();
         assert_eq!(get_code_segment_size(&statements), 6);
     }
 
@@ -637,21 +1232,21 @@ mod tests {
         const3 equ 3
         code3 load r1, =2
         ".to_string();
-        let statements = code_to_statements(&source).unwrap();
-        let relative_table = create_symbol_table(&statements).unwrap();
+        let statements = code_to_statements(&split_lines(&source, "")).unwrap();
+        let relative_table = create_symbol_table(&statements, &HashMap::new(), 0, 0).unwrap();
 
-        assert_eq!(relative_table.get("const1".into()).unwrap().offset, 1);
-        assert_eq!(relative_table.get("const2".into()).unwrap().offset, 2);
-        assert_eq!(relative_table.get("const3".into()).unwrap().offset, 3);
+        assert_eq!(relative_table.get("const1").unwrap().offset, 1);
+        assert_eq!(relative_table.get("const2").unwrap().offset, 2);
+        assert_eq!(relative_table.get("const3").unwrap().offset, 3);
 
         // Note that data2 is a 2-address long segment
-        assert_eq!(relative_table.get("data1".into()).unwrap().offset, 0);
-        assert_eq!(relative_table.get("data2".into()).unwrap().offset, 1);
-        assert_eq!(relative_table.get("data3".into()).unwrap().offset, 3);
+        assert_eq!(relative_table.get("data1").unwrap().offset, 0);
+        assert_eq!(relative_table.get("data2").unwrap().offset, 1);
+        assert_eq!(relative_table.get("data3").unwrap().offset, 3);
 
-        assert_eq!(relative_table.get("code1".into()).unwrap().offset, 1);
-        assert_eq!(relative_table.get("code2".into()).unwrap().offset, 2);
-        assert_eq!(relative_table.get("code3".into()).unwrap().offset, 4);
+        assert_eq!(relative_table.get("code1").unwrap().offset, 1);
+        assert_eq!(relative_table.get("code2").unwrap().offset, 2);
+        assert_eq!(relative_table.get("code3").unwrap().offset, 4);
     }
 
     #[test]
@@ -663,12 +1258,12 @@ mod tests {
         ds 3        ; 3-5
         data3 dc 6  ; 6
         ".to_string();
-        let statements = code_to_statements(&source).unwrap();
-        let relative_table = create_symbol_table(&statements).unwrap();
+        let statements = code_to_statements(&split_lines(&source, "")).unwrap();
+        let relative_table = create_symbol_table(&statements, &HashMap::new(), 0, 0).unwrap();
 
-        assert_eq!(relative_table.get("data1".into()).unwrap().offset, 0);
-        assert_eq!(relative_table.get("data2".into()).unwrap().offset, 2);
-        assert_eq!(relative_table.get("data3".into()).unwrap().offset, 6);
+        assert_eq!(relative_table.get("data1").unwrap().offset, 0);
+        assert_eq!(relative_table.get("data2").unwrap().offset, 2);
+        assert_eq!(relative_table.get("data3").unwrap().offset, 6);
     }
 
     #[test]
@@ -681,15 +1276,15 @@ mod tests {
         data2 dc 2
         code2 nop
         ".to_string();
-        let statements = code_to_statements(&source).unwrap();
-        let relative_table = create_symbol_table(&statements).unwrap();
-
-        assert_eq!(relative_table.get("const1".into()).unwrap().symbol_type, SymbolType::Const);
-        assert_eq!(relative_table.get("const2".into()).unwrap().symbol_type, SymbolType::Const);
-        assert_eq!(relative_table.get("data1".into()).unwrap().symbol_type, SymbolType::Data);
-        assert_eq!(relative_table.get("data2".into()).unwrap().symbol_type, SymbolType::Data);
-        assert_eq!(relative_table.get("code1".into()).unwrap().symbol_type, SymbolType::Code);
-        assert_eq!(relative_table.get("code2".into()).unwrap().symbol_type, SymbolType::Code);
+        let statements = code_to_statements(&split_lines(&source, "")).unwrap();
+        let relative_table = create_symbol_table(&statements, &HashMap::new(), 0, 0).unwrap();
+
+        assert_eq!(relative_table.get("const1").unwrap().symbol_type, SymbolType::Const);
+        assert_eq!(relative_table.get("const2").unwrap().symbol_type, SymbolType::Const);
+        assert_eq!(relative_table.get("data1").unwrap().symbol_type, SymbolType::Data);
+        assert_eq!(relative_table.get("data2").unwrap().symbol_type, SymbolType::Data);
+        assert_eq!(relative_table.get("code1").unwrap().symbol_type, SymbolType::Code);
+        assert_eq!(relative_table.get("code2").unwrap().symbol_type, SymbolType::Code);
     }
 
 
@@ -706,9 +1301,9 @@ mod tests {
 
         let absolute_table = create_absolute_symbol_table(relative_table, code_start, data_start);
 
-        assert_eq!(absolute_table.get("const".into()).unwrap().offset, 2);
-        assert_eq!(absolute_table.get("code".into()).unwrap().offset, 12);
-        assert_eq!(absolute_table.get("data".into()).unwrap().offset, 22);
+        assert_eq!(absolute_table.get("const").unwrap().offset, 2);
+        assert_eq!(absolute_table.get("code").unwrap().offset, 12);
+        assert_eq!(absolute_table.get("data").unwrap().offset, 22);
     }
 
     #[test]
@@ -720,7 +1315,7 @@ mod tests {
         symbol_table.insert("data".into(), Symbol { offset: 56, symbol_type: SymbolType::Data });
 
         // Org is set to an arbitrary nonzero value to make sure it doesn't affect label offsets anymore.
-        let b91 = build_b91(Vec::new(), Vec::new(), symbol_table, 420).unwrap();
+        let b91 = build_b91(Vec::new(), Vec::new(), &symbol_table, HashMap::new(), HashMap::new(), 420).to_string();
         let mut lines = b91.lines();
 
         // Skip until symboltable
@@ -758,12 +1353,674 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_compile_to_b91() {
+        let source = "
+        org 10
+        x dc 5
+        y ds 2
+        start load r1, x
+        store r1, y
+        svc sp, =HALT
+        ".to_string();
+        let b91 = compile_to_b91(source.clone(), &CompileOptions::default()).unwrap();
+
+        assert_eq!(b91.code_segment.start, 10);
+        assert_eq!(b91.code_segment.end, 12);
+        assert_eq!(b91.code_segment.content.len(), 3);
+        assert_eq!(b91.data_segment.start, 13);
+        assert_eq!(b91.data_segment.end, 15);
+        assert_eq!(b91.data_segment.content, vec![5, 0, 0]);
+        assert_eq!(b91.symbol_table.get("x"), Some(&13));
+        assert_eq!(b91.symbol_table.get("y"), Some(&14));
+        assert_eq!(b91.symbol_table.get("start"), Some(&10));
+
+        // The string output is the same thing, formatted.
+        assert_eq!(compile(source).unwrap(), b91.to_string());
+    }
+
+    #[test]
+    fn test_compile_comments() {
+        let source = "
+        org 100
+        x dc 5      ; x
+        y ds 2      ;   spaces around  
+        z dc 0      ;
+        const equ 5 ; constants have no address
+        load r1, x  ; first instruction
+        nop
+        svc sp, =HALT ; last instruction
+        ".to_string();
+        let b91 = compile_to_b91(source, &CompileOptions::default()).unwrap();
+
+        assert_eq!(b91.comments.len(), 4);
+        assert_eq!(b91.comments.get(&100).unwrap(), "first instruction");
+        assert_eq!(b91.comments.get(&102).unwrap(), "last instruction");
+        assert_eq!(b91.comments.get(&103).unwrap(), "x");
+        assert_eq!(b91.comments.get(&104).unwrap(), "spaces around");
+    }
+
+    #[test]
+    fn test_compile_debug_info() {
+        let source = "
+        x dc 5
+        start load r1, x
+          svc sp, =HALT
+        ".to_string();
+
+        // Off by default
+        let b91 = compile_to_b91(source.clone(), &CompileOptions::default()).unwrap();
+        assert!(b91.debug_info.is_empty());
+
+        let options = CompileOptions { debug_info: true, source_name: "test.k91".into(), ..Default::default() };
+        let b91 = compile_to_b91(source, &options).unwrap();
+        assert_eq!(b91.debug_info.len(), 3);
+        assert_eq!(b91.debug_info.get(&0).unwrap(), &SourceLocation { file: "test.k91".into(), line: 3, column: 8 });
+        assert_eq!(b91.debug_info.get(&1).unwrap(), &SourceLocation { file: "test.k91".into(), line: 4, column: 10 });
+        assert_eq!(b91.debug_info.get(&2).unwrap(), &SourceLocation { file: "test.k91".into(), line: 2, column: 8 });
+    }
+
+    #[test]
+    fn test_compile_expressions() {
+        let source = "
+        BUFSIZE equ 4
+        LAST    equ BUFSIZE-1
+        table   dc 7
+        arr     ds BUFSIZE*2
+        ptr     dc table+4
+                load r1, =LAST
+                store r1, arr+2(r2)
+                load r2, =(BUFSIZE << 2) | 1
+                svc sp, =HALT
+        ".to_string();
+        let b91 = compile_to_b91(source, &CompileOptions::default()).unwrap();
+
+        assert_eq!(b91.symbol_table.get("LAST"), Some(&3));
+        assert_eq!(b91.code_segment.content[0], 0x02200003);
+        assert_eq!(b91.code_segment.content[1], 0x01220007);
+        assert_eq!(b91.code_segment.content[2], 0x02400011);
+        assert_eq!(b91.data_segment.content.len(), 10);
+        assert_eq!(b91.data_segment.content[9], 8);
+    }
+
+    #[test]
+    fn test_compile_forward_references() {
+        let source = "
+        org 100
+        A       equ B+1
+        B       equ SIZE*2
+        buf     ds SIZE
+        after   dc 0
+        LEN     equ after-buf
+        ENTRY   equ start
+        start   load r1, =A
+                svc sp, =HALT
+        SIZE    equ 3
+        ".to_string();
+        let b91 = compile_to_b91(source, &CompileOptions::default()).unwrap();
+
+        assert_eq!(b91.symbol_table.get("SIZE"), Some(&3));
+        assert_eq!(b91.symbol_table.get("B"), Some(&6));
+        assert_eq!(b91.symbol_table.get("A"), Some(&7));
+        assert_eq!(b91.symbol_table.get("buf"), Some(&102));
+        assert_eq!(b91.symbol_table.get("after"), Some(&105));
+        assert_eq!(b91.symbol_table.get("LEN"), Some(&3));
+        assert_eq!(b91.symbol_table.get("ENTRY"), Some(&100));
+        assert_eq!(b91.code_segment.content[0], 0x02200007);
+    }
+
+    #[test]
+    fn test_compile_circular_definition() {
+        let source = "
+        A       equ B+1
+        B       equ C
+        C       equ A
+        D       equ A
+        ".to_string();
+        let errors = compile(source).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].code, ErrorCode::CircularDefinition);
+        assert_eq!(errors[0].message, "Circular definition: A -> B -> C -> A");
+        assert_eq!(errors[0].line, 2);
+
+        // Size of a DS can't depend on labels after it.
+        let source = "
+        buf     ds N
+        x       dc 0
+        N       equ x
+        ".to_string();
+        let errors = compile(source).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message, "Circular definition: DS on line 2 -> N -> x -> DS on line 2");
+
+        // DS statements from the same macro line are still different statements.
+        let source = "
+        MACRO BUF n
+                ds n
+        ENDM
+                BUF nope
+                BUF also_nope
+        ".to_string();
+        let errors = compile(source).unwrap_err();
+        let messages: Vec<&str> = errors.iter().map(|e| e.message.as_str()).collect();
+        assert_eq!(messages, vec!["Undefined symbol: 'nope'", "Undefined symbol: 'also_nope'"]);
+    }
+
+    #[test]
+    fn test_compile_include() {
+        let files = HashMap::from([
+            ("io.k91".to_string(), "print   out r1, =CRT\n        exit sp, =0".to_string()),
+            ("broken.k91".to_string(), "\n        load r1, nowhere".to_string()),
+        ]);
+        let options = CompileOptions {
+            source_name: "main.k91".into(),
+            debug_info: true,
+            file_resolver: Some(Arc::new(files)),
+            ..Default::default()
+        };
+
+        let source = "
+                load r1, =5
+                call sp, print
+                svc sp, =HALT
+                INCLUDE \"io.k91\"
+        ".to_string();
+        let b91 = compile_to_b91(source, &options).unwrap();
+        assert_eq!(b91.code_segment.content.len(), 5);
+        assert_eq!(b91.symbol_table.get("print"), Some(&3));
+        assert_eq!(b91.debug_info.get(&3).unwrap(), &SourceLocation { file: "io.k91".into(), line: 1, column: 0 });
+
+        // Errors say which file they're in.
+        let errors = compile_to_b91("INCLUDE \"broken.k91\"".into(), &options).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].file, "broken.k91");
+        assert_eq!(errors[0].line, 2);
+        assert_eq!(errors[0].to_string(), "error[E015]: broken.k91, line 2: Undefined symbol: 'nowhere'\n    help: Address must be a number, a builtin constant, or a symbol defined with EQU, DC, DS, or a code label.");
+    }
+
+    #[test]
+    fn test_compile_macros() {
+        let source = "
+        MACRO PRINT value
+                load r1, value
+                out r1, =CRT
+        ENDM
+        x       dc 7
+        start   PRINT x
+                PRINT =3
+                svc sp, =HALT
+        ".to_string();
+        let b91 = compile_to_b91(source, &CompileOptions::default()).unwrap();
+        assert_eq!(b91.code_segment.content.len(), 5);
+        assert_eq!(b91.symbol_table.get("start"), Some(&0));
+        assert_eq!(disassemble_instruction(b91.code_segment.content[0]), "LOAD  R1,  5");
+        assert_eq!(disassemble_instruction(b91.code_segment.content[2]), "LOAD  R1, =3");
+
+        // An expression argument stays one value inside the body's expression.
+        let source = "
+        MACRO DOUBLE n
+                load r1, =n*2
+        ENDM
+                DOUBLE 1+1
+        ".to_string();
+        let b91 = compile_to_b91(source, &CompileOptions::default()).unwrap();
+        assert_eq!(disassemble_instruction(b91.code_segment.content[0]), "LOAD  R1, =4");
+
+        // Calls can be labeled even if the macro starts with a label, local or not.
+        let source = "
+        MACRO COUNTDOWN
+        .loop   sub r1, =1
+                jpos r1, .loop
+        ENDM
+        main    load r1, =3
+        wait    COUNTDOWN
+                svc sp, =HALT
+        ".to_string();
+        let b91 = compile_to_b91(source, &CompileOptions::default()).unwrap();
+        assert_eq!(b91.symbol_table.get("wait"), Some(&1));
+        assert_eq!(disassemble_instruction(b91.code_segment.content[2]), "JPOS  R1,  1");
+
+        // Errors point at the macro body, with a note about the call.
+        let source = "
+        MACRO LOADX
+                load r1, x
+        ENDM
+                LOADX
+        ".to_string();
+        let errors = compile(source).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].line, 3);
+        assert_eq!(errors[0].notes, vec!["In macro 'LOADX', called from line 5"]);
+    }
+
+    #[test]
+    fn test_compile_conditional_macros() {
+        // Each branch defines its own version of the macro.
+        let source = "
+                IF DEBUG
+        MACRO TRACE
+                out r1, =CRT
+        ENDM
+                ELSE
+        MACRO TRACE
+                nop
+        ENDM
+                ENDIF
+                TRACE
+                IF 0
+                INCLUDE \"missing.k91\"
+                ENDIF
+                svc sp, =HALT
+        ".to_string();
+        let mut options = CompileOptions::default();
+        options.defines.insert("DEBUG".into(), 0);
+        let release = compile_to_b91(source.clone(), &options).unwrap();
+        assert_eq!(disassemble_instruction(release.code_segment.content[0]), "NOP  ");
+
+        options.defines.insert("DEBUG".into(), 1);
+        let debug = compile_to_b91(source, &options).unwrap();
+        assert_eq!(disassemble_instruction(debug.code_segment.content[0]), "OUT   R1, =0");
+
+        // IF in a macro body is checked on each call.
+        let source = "
+        MACRO PUSHN n
+                IF n > 1
+                push sp, r1
+                ENDIF
+                push sp, r1
+        ENDM
+                PUSHN 1
+                PUSHN 2
+        ".to_string();
+        let b91 = compile_to_b91(source, &CompileOptions::default()).unwrap();
+        assert_eq!(b91.code_segment.content.len(), 3);
+
+        // Macros have to be defined before they're called.
+        let errors = compile("        SPIN\n        MACRO SPIN\n        nop\n        ENDM".into()).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].line, 1);
+        assert_eq!(errors[0].code, ErrorCode::MissingKeyword);
+    }
+
+    #[test]
+    fn test_compile_conditionals() {
+        let source = "
+                IFNDEF DEBUG
+        DEBUG   equ 0
+                ENDIF
+                load r1, =LEVEL
+                IF DEBUG
+                out r1, =CRT
+                ENDIF
+                svc sp, =HALT
+        ".to_string();
+        let mut options = CompileOptions::default();
+        options.defines.insert("LEVEL".into(), 3);
+        let release = compile_to_b91(source.clone(), &options).unwrap();
+        assert_eq!(release.code_segment.content.len(), 2);
+        assert_eq!(release.symbol_table.get("DEBUG"), Some(&0));
+        assert_eq!(disassemble_instruction(release.code_segment.content[0]), "LOAD  R1, =3");
+
+        options.defines.insert("DEBUG".into(), 1);
+        let debug = compile_to_b91(source, &options).unwrap();
+        assert_eq!(debug.code_segment.content.len(), 3);
+        assert_eq!(debug.symbol_table.get("DEBUG"), Some(&1));
+
+        // Defines can't be redefined.
+        let errors = compile_to_b91("DEBUG equ 0".into(), &options).unwrap_err();
+        assert_eq!(errors[0].code, ErrorCode::MultipleDefinition);
+    }
+
+    #[test]
+    fn test_compile_local_labels() {
+        let source = "
+        count   dc 3
+        .limit  dc 10
+        first   load r1, count
+        .loop   sub r1, =1
+                jnzer r1, .loop
+        second  load r2, count.limit
+        STEP    equ 2
+        .loop   sub r2, =STEP
+                jpos r2, .loop
+                svc sp, =HALT
+        ".to_string();
+        let b91 = compile_to_b91(source, &CompileOptions::default()).unwrap();
+        assert_eq!(b91.symbol_table.get("first.loop"), Some(&1));
+        assert_eq!(b91.symbol_table.get("second.loop"), Some(&4));
+        assert_eq!(b91.symbol_table.get("count.limit"), Some(&8));
+        assert_eq!(b91.symbol_table.get(".loop"), None);
+        assert_eq!(disassemble_instruction(b91.code_segment.content[2]), "JNZER R1,  1");
+        assert_eq!(disassemble_instruction(b91.code_segment.content[5]), "JPOS  R2,  4");
+
+        // No scope
+        let errors = compile(".loop nop\nmain nop\n. nop".into()).unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].code, ErrorCode::LocalLabel);
+        assert_eq!(errors[1].code, ErrorCode::LocalLabel);
+
+        // Local labels of another scope aren't visible.
+        let errors = compile("a nop\n.x nop\nb jump .x".into()).unwrap_err();
+        assert_eq!(errors[0].code, ErrorCode::UndefinedSymbol);
+    }
+
+    #[test]
+    fn test_compile_numeric_labels() {
+        let source = "
+                load r1, =3
+        1:      sub r1, =1
+                jzer r1, 1f
+                jump 1b
+        1:      load r2, =2
+        1:      sub r2, =1
+                jpos r2, 1b
+                svc sp, =HALT
+        ".to_string();
+        let b91 = compile_to_b91(source, &CompileOptions::default()).unwrap();
+        assert_eq!(b91.symbol_table.get("_1_1"), Some(&1));
+        assert_eq!(b91.symbol_table.get("_1_2"), Some(&4));
+        assert_eq!(b91.symbol_table.get("_1_3"), Some(&5));
+        assert_eq!(disassemble_instruction(b91.code_segment.content[2]), "JZER  R1,  4");
+        assert_eq!(disassemble_instruction(b91.code_segment.content[3]), "JUMP   1");
+        assert_eq!(disassemble_instruction(b91.code_segment.content[6]), "JPOS  R2,  5");
+
+        // Numeric labels don't start a scope.
+        let b91 = compile_to_b91("main nop\n1: nop\n.end jump 1b".into(), &CompileOptions::default()).unwrap();
+        assert_eq!(b91.symbol_table.get("main.end"), Some(&2));
+
+        let errors = compile("jump 1b\n1: jump 1f".into()).unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].message, "No label '1:' before '1b'");
+        assert_eq!(errors[1].message, "No label '1:' after '1f'");
+
+        // Uppercase references, as in Titokone-style source.
+        let source = "1: SUB R1, =1\nJZER R1, 1F\nJUMP 1B\n1: SVC SP, =HALT".to_string();
+        for options in [CompileOptions::default(), CompileOptions { titokone: true, ..Default::default() }] {
+            let b91 = compile_to_b91(source.clone(), &options).unwrap();
+            assert_eq!(disassemble_instruction(b91.code_segment.content[1]), "JZER  R1,  3");
+            assert_eq!(disassemble_instruction(b91.code_segment.content[2]), "JUMP   0");
+        }
+    }
+
+    #[test]
+    fn test_compile_data_lists() {
+        let source = r#"
+        table   dc 1, 2, table+3
+        buf     ds 3, -1
+        msg     string "Hi,\n"
+        name    stringz "a;b"
+        end     dc 0
+                load r1, end
+        "#.to_string();
+        let b91 = compile_to_b91(source, &CompileOptions::default()).unwrap();
+        assert_eq!(b91.data_segment.content, vec![1, 2, 4, -1, -1, -1, 72, 105, 44, 10, 97, 59, 98, 0, 0]);
+        assert_eq!(b91.symbol_table.get("buf"), Some(&4));
+        assert_eq!(b91.symbol_table.get("msg"), Some(&7));
+        assert_eq!(b91.symbol_table.get("name"), Some(&11));
+        assert_eq!(b91.symbol_table.get("end"), Some(&15));
+
+        let source = r#"
+                dc
+                ds 2, 1, 2
+                ds 2, INT_MAX*4
+                string ""
+                string "\q"
+                stringz "a" "b"
+                nop
+        "#.to_string();
+        let codes: Vec<ErrorCode> = compile(source).unwrap_err().iter().map(|e| e.code).collect();
+        assert_eq!(codes, vec![
+            ErrorCode::MissingValue,
+            ErrorCode::TooManyValues,
+            ErrorCode::OutOfRange,
+            ErrorCode::InvalidString,
+            ErrorCode::InvalidString,
+            ErrorCode::TooManyValues,
+        ]);
+
+        // Spacing around an operator doesn't matter, and values need commas between them.
+        let source = "a dc 1 - 2\nb dc 1, -2\nload r1, a +1".to_string();
+        let b91 = compile_to_b91(source, &CompileOptions::default()).unwrap();
+        assert_eq!(b91.data_segment.content, vec![-1, 1, -2]);
+        assert_eq!(disassemble_instruction(b91.code_segment.content[0]), "LOAD  R1,  2");
+        let errors = compile("x dc 1 2\ny ds 2 1\nz dc 1 -2".into()).unwrap_err();
+        assert_eq!(errors.len(), 3);
+        assert_eq!(errors[0].message, "Expected ',' before '2'");
+        assert_eq!(errors[1].message, "Expected ',' before '1'");
+        assert_eq!(errors[2].message, "Expected ',' before '-2'");
+    }
+
+    #[test]
+    fn test_compile_case_insensitive() {
+        let source = "
+        Count   EQU 2
+        X       DC count
+        Start   LOAD R1, x
+                OUT r1, =crt
+                JUMP start
+        ".to_string();
+        let options = CompileOptions { case_sensitive: false, ..Default::default() };
+        let b91 = compile_to_b91(source.clone(), &options).unwrap();
+        assert_eq!(b91.symbol_table.get("start"), Some(&0));
+        assert_eq!(b91.symbol_table.get("x"), Some(&3));
+        assert_eq!(b91.data_segment.content, vec![2]);
+
+        // Same source is broken when case matters.
+        assert!(compile(source).is_err());
+
+        let errors = compile_to_b91("a nop\nA nop".into(), &options).unwrap_err();
+        assert_eq!(errors[0].code, ErrorCode::MultipleDefinition);
+    }
+
+    #[test]
+    fn test_compile_target_isa() {
+        let source = "
+                load r1, =1
+                hlt
+                iexit sp, =0
+                hcf
+        ".to_string();
+        assert!(compile(source.clone()).is_ok());
+
+        let options = CompileOptions { target_isa: TargetIsa::Classic, ..Default::default() };
+        let errors = compile_with_options(source, &options).unwrap_err();
+        assert_eq!(errors.len(), 3);
+        assert!(errors.iter().all(|e| e.code == ErrorCode::UnsupportedInstruction));
+        assert_eq!(errors[0].line, 3);
+        assert_eq!(errors[0].message, "'HLT' is not in the classic instruction set");
+
+        assert_eq!(TargetIsa::from_str("Classic"), Ok(TargetIsa::Classic));
+        assert!(TargetIsa::from_str("modern").is_err());
+    }
+
+    #[test]
+    fn test_compile_warnings() {
+        let source = "
+        main    load r1, =1
+                svc sp, =HALT
+        unused  dc 0
+        ".to_string();
+        let warnings = compile_full(source.clone(), &CompileOptions::default()).unwrap().warnings;
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].severity, Severity::Warning);
+        assert_eq!(warnings[0].lint, Some(Lint::UnusedLabel));
+        assert_eq!(warnings[0].line, 4);
+        assert_eq!(warnings[0].to_string(), "warning[unused-label]: Line 4: Label 'unused' is never used");
+
+        // Disabled
+        let options = CompileOptions { lints: HashSet::new(), ..Default::default() };
+        let warnings = compile_full(source.clone(), &options).unwrap().warnings;
+        assert!(warnings.is_empty());
+
+        // As errors
+        let options = CompileOptions { warnings_as_errors: true, ..Default::default() };
+        let errors = compile_with_options(source, &options).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].severity, Severity::Error);
+        assert_eq!(errors[0].to_string(), "error[unused-label]: Line 4: Label 'unused' is never used");
+    }
+
+    #[test]
+    fn test_compile_constants_section() {
+        let source = "SIZE equ 3\nx dc SIZE\nload r1, x".to_string();
+        let b91 = compile_with_options(source.clone(), &CompileOptions::default()).unwrap();
+        assert!(!b91.contains("___constants___"));
+
+        let options = CompileOptions { constants_section: true, titokone: true, ..Default::default() };
+        let b91 = compile_to_b91(source, &options).unwrap();
+        assert_eq!(b91.constants, HashSet::from(["size".to_string()]));
+    }
+
+    #[test]
+    fn test_compile_full() {
+        let source = "
+        main    load r1, =1
+                svc sp, =HALT
+        unused  dc 0
+        ".to_string();
+        let b91 = compile_to_b91(source.clone(), &CompileOptions::default()).unwrap();
+        let output = compile_full(source, &CompileOptions::default()).unwrap();
+        assert_eq!(output.b91, b91);
+        assert!(output.listing.starts_with(" Addr"));
+        assert_eq!(output.warnings.len(), 1);
+        assert_eq!(output.warnings[0].lint, Some(Lint::UnusedLabel));
+    }
+
+    #[test]
+    fn test_compile_titokone() {
+        let options = CompileOptions { titokone: true, ..Default::default() };
+        let source = "
+        X       DC 5        ; comment
+        Start   LOAD R1, x
+                OUT r1, =Crt
+                SVC SP, =halt
+        ".to_string();
+        let b91 = compile_with_options(source, &options).unwrap();
+        assert_eq!(b91, "___b91___
+___code___
+0 2
+36175875
+69206016
+1891631115
+___data___
+3 3
+5
+___symboltable___
+halt 11
+crt 0
+start 0
+x 3
+___end___
+");
+
+        let errors = compile_with_options("hlt".into(), &options).unwrap_err();
+        assert_eq!(errors[0].code, ErrorCode::UnsupportedInstruction);
+    }
+
+    #[test]
+    fn test_compile_expression_errors() {
+        let source = "
+        big     equ 40000
+        zero    dc 1/0
+                load r1, =SHRT_MAX*2+2
+                load r1, =(1+
+        ".to_string();
+        let errors = compile(source).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].code, ErrorCode::OutOfRange);
+        assert_eq!(errors[0].line, 2);
+
+        let source = "
+        zero    dc 1/0
+                load r1, =SHRT_MAX*2+2
+                load r1, =(1+
+        ".to_string();
+        let codes: Vec<ErrorCode> = compile(source).unwrap_err().iter().map(|e| e.code).collect();
+        assert_eq!(codes, vec![ErrorCode::DivisionByZero, ErrorCode::OutOfRange, ErrorCode::InvalidExpression]);
+    }
+
     #[test]
     fn test_label_no_instruction() {
         let source = "
         not_a_keyword  ;
         ".to_string();
-        assert!(code_to_statements(&source).is_err());
+        assert!(code_to_statements(&split_lines(&source, "")).is_err());
+    }
+
+    #[test]
+    fn test_compile_returns_all_diagnostics() {
+        let source = "
+        load r1, =1
+        load r9, =1
+        store r1, nowhere
+        svc sp, =HALT
+        ".to_string();
+        let diagnostics = compile(source).unwrap_err();
+        assert_eq!(diagnostics.len(), 2);
+
+        assert_eq!(diagnostics[0].code, ErrorCode::InvalidRegister);
+        assert_eq!(diagnostics[0].line, 3);
+        assert_eq!(diagnostics[0].token.as_deref(), Some("r9"));
+        assert_eq!(diagnostics[0].columns, 13..15);
+
+        assert_eq!(diagnostics[1].code, ErrorCode::UndefinedSymbol);
+        assert_eq!(diagnostics[1].line, 4);
+        assert_eq!(diagnostics[1].token.as_deref(), Some("nowhere"));
+        assert_eq!(diagnostics[1].columns, 18..25);
+    }
+
+    #[test]
+    fn test_compile_keeps_going_after_errors() {
+        // Every broken constant is reported, not just the first.
+        let source = "
+        x equ 0xfffff
+        y equ 1
+        z equ
+        ".to_string();
+        let diagnostics = compile(source).unwrap_err();
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(diagnostics[0].line, 2);
+        assert_eq!(diagnostics[1].line, 4);
+
+        let source = "
+        foo bar
+        nop
+        r1 nop
+        label
+        ".to_string();
+        let diagnostics = code_to_statements(&split_lines(&source, "")).unwrap_err();
+        assert_eq!(diagnostics.len(), 3);
+        assert_eq!(diagnostics[0].code, ErrorCode::UnknownKeyword);
+        assert_eq!(diagnostics[1].code, ErrorCode::UnexpectedRegister);
+        assert_eq!(diagnostics[2].code, ErrorCode::MissingKeyword);
+    }
+
+    #[test]
+    fn test_compile_error_limit() {
+        let source = "load r9, =1\n".repeat(10);
+        let options = CompileOptions { error_limit: Some(3), ..Default::default() };
+        let diagnostics = compile_with_options(source.clone(), &options).unwrap_err();
+        assert_eq!(diagnostics.len(), 4);
+        assert_eq!(diagnostics[3].code, ErrorCode::TooManyErrors);
+
+        let options = CompileOptions { error_limit: None, ..Default::default() };
+        let diagnostics = compile_with_options(source, &options).unwrap_err();
+        assert_eq!(diagnostics.len(), 10);
+    }
+
+    #[test]
+    fn test_multiple_definition_reports_each_repeat() {
+        let source = "
+        x dc 1
+        x dc 2
+        x nop
+        ".to_string();
+        let statements = code_to_statements(&split_lines(&source, "")).unwrap();
+        let diagnostics = assert_no_multiple_definition(&statements, &HashMap::new()).unwrap_err();
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(diagnostics[0].line, 3);
+        assert_eq!(diagnostics[1].line, 4);
+        assert!(diagnostics.iter().all(|d| d.code == ErrorCode::MultipleDefinition));
     }
 
     #[test]
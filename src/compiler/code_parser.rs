@@ -7,116 +7,134 @@
 //! TiToMachine k91 assembler - Instruction parsing module.
 //!
 use std::collections::HashMap;
+use std::ops::Range;
 use std::str::FromStr;
-use crate::compiler::{Statement, str_to_builtin_const, str_to_integer, Symbol};
-use crate::instructions::{OpCode, Register};
-
-pub fn parse_instruction(statement: Statement, symbol_table: &HashMap<String, Symbol>) -> Result<i32, String>
+use crate::compiler::{Diagnostic, ErrorCode, Statement, str_to_builtin_const, Symbol};
+use crate::compiler::expression::{eval_expression, OPERATOR_CHARS};
+use crate::compiler::tokenizer::Token;
+use crate::instructions::{AddressingMode, OpCode, Register, TTK91Instruction};
+
+/// Turn a code statement into an instruction word.
+/// Both operands are checked even if the first one is broken, so all of their errors get reported.
+pub fn parse_instruction(statement: &Statement, symbol_table: &HashMap<String, Symbol>) -> Result<i32, Vec<Diagnostic>>
 {
-    let mut words = statement.words.clone();
-    let keyword_string = statement.words[0].to_uppercase();
-    let keyword = keyword_string.as_str();
+    let mut errors = Vec::new();
+    let keyword = statement.words[0].text.to_uppercase();
 
-    // Remove oper keyword
-    words.remove(0);
-    let line = statement.line;
+    // Operand words come after oper keyword
+    let operands = &statement.words[1..];
 
     // Get opcode
-    let opcode;
-    match OpCode::from_str(keyword) {
-        Ok(op) => opcode = op,
-        Err(e) => return Err(format!("Line {}: {}", line, e))
-    }
+    let opcode = match OpCode::from_str(&keyword) {
+        Ok(op) => op,
+        Err(e) => return Err(vec![statement.error_at(&statement.words[0], ErrorCode::Internal, e)])
+    };
 
     // Assert correct number of operands
-    if words.len() != opcode.get_operand_count() {
-        return Err(format!("Line {}: Invalid number of operands for {}. Expected {}, but got {}", line, keyword, opcode.get_operand_count(), words.len()));
+    if operands.len() != opcode.get_operand_count() {
+        return Err(vec![statement.error(ErrorCode::OperandCount, format!("Invalid number of operands for {}. Expected {}, but got {}", keyword, opcode.get_operand_count(), operands.len()))]);
     }
 
-    // Get operand words
-    let op1: String;
-    let op2: String;
-
-    match words.len() {
-        0 => {
-            op1 = "R0".to_string();
-            op2 = String::new();
-        }
+    // Get operand words. Missing first operand means R0.
+    let (op1, op2): (Option<&Token>, Option<&Token>) = match operands.len() {
+        0 => (None, None),
         1 => {
             // Some jumps use op2 but not op1. Swap them, if appropriate.
             if opcode.is_op2_only() {
-                op1 = "R0".to_string();
-                op2 = words[0].clone();
+                (None, Some(&operands[0]))
             } else {
-                op1 = words[0].to_uppercase();
-                op2 = String::new();
+                (Some(&operands[0]), None)
             }
         }
-        2 => {
-            op1 = words[0].to_uppercase();
-            op2 = words[1].clone();
-        }
-        _ => panic!("Line {}: wtf, word count is '{}'. '{:?}'", line, words.len(), words)
-    }
+        2 => (Some(&operands[0]), Some(&operands[1])),
+        _ => panic!("Line {}: wtf, word count is '{}'. '{:?}'", statement.line, operands.len(), operands)
+    };
 
     // Get first register
-    let rj;
-    match Register::from_str(op1.as_str()) {
-        Ok(register) => rj = register,
-        Err(e) => return Err(format!("Line {}: {}", line, e))
-    }
+    let rj = match op1 {
+        None => Register::R0,
+        Some(op1) => match Register::from_str(&op1.text) {
+            Ok(register) => register,
+            Err(e) => {
+                errors.push(statement.error_at(op1, ErrorCode::InvalidRegister, e));
+                Register::R0
+            }
+        }
+    };
 
     // Parse op2: Ri, mode, addr
     let mode;
     let ri;
-    let addr: i32;
+    let addr: i64;
 
-    if op2.is_empty() {
-        mode = opcode.get_default_mode();
-        ri = Register::R0;
-        addr = 0;
-    } else if let Ok(parsed) = parse_op2(op2.as_str()) {
+    match op2 {
+        None => {
+            mode = opcode.get_default_mode();
+            ri = Register::R0;
+            addr = 0;
+        }
+        Some(op2) => {
+            let parsed = match parse_op2(&op2.text) {
+                Ok(parsed) => parsed,
+                Err(e) => {
+                    errors.push(statement.error_at(&op2.slice(e.columns), ErrorCode::InvalidOperand, format!("Couldn't parse second operand: {}", e.message)));
+                    return Err(errors);
+                }
+            };
 
-        // Mode
-        mode = opcode.get_default_mode() + parsed.mode;
+            // Mode
+            mode = opcode.get_default_mode() + parsed.mode;
 
-        // Register
-        ri = parsed.register;
+            // Register
+            ri = parsed.register;
 
-        // Address
-        if parsed.addr.as_str() == "" {
-            // (is empty)
-            addr = 0;
-        } else if let Ok(val) = str_to_builtin_const(&parsed.addr) {
-            // (is builtin const)
-            addr = val;
-        } else if let Some(symbol) = symbol_table.get(&parsed.addr) {
-            // (is in symbol table)
-            addr = symbol.offset
-        } else if let Ok(val) = str_to_integer(parsed.addr.as_str()) {
-            // (is number)
-            addr = val;
-        } else {
-            return Err(format!("Line {}: invalid address: {}", line, parsed.addr));
-        }
-    } else {
-        return Err(format!("Line {}: Couldn't parse second operand: {}", line, op2));
-    }
+            // Address
+            let addr_token = op2.slice(parsed.addr_columns);
+            if parsed.addr.as_str() == "" {
+                // (is empty)
+                addr = 0;
+            } else {
+                // (is an expression of numbers, builtin consts, and symbols)
+                let lookup = |name: &str| {
+                    let name = statement.qualify(name);
+                    str_to_builtin_const(&name).ok()
+                        .or_else(|| symbol_table.get(name.as_ref()).map(|symbol| symbol.offset))
+                };
+                match eval_expression(&parsed.addr, lookup) {
+                    Ok(value) => addr = value,
+                    Err(e) => {
+                        let mut error = statement.error_at(&addr_token.slice(e.columns), e.code, e.message);
+                        if e.code == ErrorCode::UndefinedSymbol {
+                            error = error.with_help("Address must be a number, a builtin constant, or a symbol defined with EQU, DC, DS, or a code label.");
+                        }
+                        errors.push(error);
+                        return Err(errors);
+                    }
+                }
+            }
 
-    if mode < 0 && mode > 2 {
-        return Err(format!("Line {}: Mode {} is out of range", line, mode));
+            if !(0..=2).contains(&mode) {
+                errors.push(statement.error_at(op2, ErrorCode::InvalidOperand, format!("Addressing mode is out of range for {}", keyword))
+                    .with_help(format!("{} needs a memory address. Immediate values and direct registers are not allowed.", keyword)));
+            }
+            if addr < i16::MIN as i64 || addr > u16::MAX as i64 {
+                errors.push(statement.error_at(&addr_token, ErrorCode::OutOfRange, format!("Address {} is out of range", addr)));
+            }
+        }
     }
-    if addr < i16::MIN as i32 && addr > u16::MAX as i32 {
-        return Err(format!("Line {}: Address {} is out of range", line, addr));
+    if !errors.is_empty() {
+        return Err(errors);
     }
 
-    let mut value;
-    value = (opcode as i32) << 24;
-    value += (rj as i32) << 21;
-    value += mode << 19;
-    value += (ri as i32) << 16;
-    value += addr & 0xffff;
-    Ok(value)
+    let instruction = TTK91Instruction {
+        opcode,
+        rj,
+        // Mode was checked above.
+        mode: AddressingMode::try_from(mode).unwrap(),
+        ri,
+        addr: addr as i16,
+    };
+    Ok(instruction.encode())
 }
 
 
@@ -124,90 +142,123 @@ pub fn parse_instruction(statement: Statement, symbol_table: &HashMap<String, Sy
 struct Op2 {
     pub mode: i32,
     pub addr: String,
+    /// Where the address is within the operand.
+    pub addr_columns: Range<usize>,
     pub register: Register,
 }
 
+/// Used by parse_op2()
+#[derive(Debug)]
+struct Op2Error {
+    pub message: String,
+    /// Where the problem is within the operand.
+    pub columns: Range<usize>,
+}
+
+impl Op2Error {
+    fn new(message: impl Into<String>, columns: Range<usize>) -> Self {
+        Op2Error {
+            message: message.into(),
+            columns,
+        }
+    }
+}
+
 /// Parse second operand: "=123(R2)"
-fn parse_op2(input_str: &str) -> Result<Op2, String> {
+/// The address part is left as text, because it may be an expression: "=SIZE-1", "arr+2(R2)".
+fn parse_op2(input_str: &str) -> Result<Op2, Op2Error> {
     let mut mode: i32 = 0;
-    let mut addr = String::new();
-    //let mut chars = input_str.chars();
 
-    let mut text = input_str.to_string();
+    // Remaining text, and where it starts in input_str.
+    let mut text = input_str;
+    let mut offset = 0;
 
     // Catch mode sign
-    if input_str.starts_with("=") {
+    if input_str.starts_with('=') {
         mode = -1;
-        text.remove(0);
-    } else if input_str.starts_with("@") {
+        text = &text[1..];
+        offset += 1;
+    } else if input_str.starts_with('@') {
         mode = 1;
-        text.remove(0);
-    }
-
-    // Catch minus sign
-    if input_str.starts_with("-") {
-        addr += "-";
-        text.remove(0);
+        text = &text[1..];
+        offset += 1;
     }
 
     // We're done already: Second operand text is a register with no address.
-    if let Ok(register) = Register::from_str(text.as_str()) {
-
-        // Do not allow negative direct register addressing "-R1"
-        if addr.as_str() == "-" {
-            return Err(format!("Negative direct register addressing '{}' is not allowed. The minus sign only affects address portion.", input_str));
-        }
-
+    if let Ok(register) = Register::from_str(text) {
         return Ok(Op2 {
             mode: mode - 1, // Register only decrements because of direct reg addressing
-            addr,
+            addr: String::new(),
+            addr_columns: offset..offset,
             register,
         });
     }
 
-    let register;
-    // Second operand _contains_ register in parentheses
-    if let Some((before_open, after_open)) = text.split_once('(') {
-        match after_open.split_once(')') {
-            Some((register_string, after_close)) => {
-                register = Register::from_str(register_string)?;
-
-                // Err: There's stuff on both sides of the parentheses!
-                if !before_open.is_empty() && !after_close.is_empty() {
-                    return Err(format!("Failed to parse second operand: '{}'", input_str));
-                }
-
-                // Nothing outside parentheses; we're done
-                if before_open.is_empty() && after_close.is_empty() {
-                    return Ok(Op2 {
-                        mode,
-                        addr,
-                        register,
-                    });
-                }
+    // Do not allow negative direct register addressing "-R1"
+    if let Some(unsigned) = text.strip_prefix('-') {
+        if Register::from_str(unsigned).is_ok() {
+            return Err(Op2Error::new(format!("Negative direct register addressing '{}' is not allowed. The minus sign only affects address portion.", input_str), 0..input_str.len()));
+        }
+    }
 
-                // One side is empty and one is not.
-                text = before_open.to_string() + after_close;
-            }
-            None => return Err("Unclosed parentheses".to_string())
+    // Look for index register in parentheses: "addr(R2)" or "(R2)addr".
+    // Parentheses that aren't right after a value belong to the address expression: "(SIZE-1)*2"
+    let mut register = Register::R0;
+    let mut addr_columns = 0..text.len();
+    if let Some(open) = find_trailing_group(text) {
+        let register_string = &text[open + 1..text.len() - 1];
+        let before_open = &text[..open];
+        let follows_value = before_open.chars().last()
+            .is_some_and(|c| !c.is_whitespace() && !OPERATOR_CHARS.contains(&c));
+        if follows_value || (before_open.is_empty() && Register::from_str(register_string).is_ok()) {
+            let register_start = offset + open + 1;
+            register = match Register::from_str(register_string) {
+                Ok(register) => register,
+                Err(e) => return Err(Op2Error::new(e, register_start..register_start + register_string.len())),
+            };
+            addr_columns = 0..open;
+        }
+    } else if let Some((register_string, _)) = text.strip_prefix('(').and_then(|rest| rest.split_once(')')) {
+        if let Ok(parsed) = Register::from_str(register_string) {
+            register = parsed;
+            addr_columns = register_string.len() + 2..text.len();
         }
-    } else {
-        register = Register::R0;
     }
 
-    // _No register_ in second operand. It's just address.
-    addr += text.as_str();
     Ok(Op2 {
         mode,
-        addr,
+        addr: text[addr_columns.clone()].to_string(),
+        addr_columns: offset + addr_columns.start..offset + addr_columns.end,
         register,
     })
 }
 
+/// If text ends with a parenthesized group, find where the group opens.
+fn find_trailing_group(text: &str) -> Option<usize> {
+    if !text.ends_with(')') {
+        return None;
+    }
+    let mut depth = 0;
+    for (i, c) in text.char_indices().rev() {
+        match c {
+            ')' => depth += 1,
+            '(' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => (),
+        }
+    }
+    None
+}
+
 
 #[cfg(test)]
 mod tests {
     use crate::compiler::Keyword;
+    use crate::compiler::tokenizer::tokenize_line;
     use super::*;
     /*
     Addressing modes require some careful testing.
@@ -363,19 +414,50 @@ mod tests {
     fn test_parse_instruction() {
         // Dummy symbol table
         let map = Default::default();
-        assert_eq!(parse_instruction(dummy_statement("add r1 =0"), &map).unwrap(), 287309824);
-        assert_eq!(parse_instruction(dummy_statement("add r1 @(r1)"), &map).unwrap(), 288423936);
-        assert_eq!(parse_instruction(dummy_statement("store r1 @0"), &map).unwrap(), 19398656);
-        assert_eq!(parse_instruction(dummy_statement("store r1 @(r1)"), &map).unwrap(), 19464192);
+        assert_eq!(parse_instruction(&dummy_statement("add r1 =0"), &map).unwrap(), 287309824);
+        assert_eq!(parse_instruction(&dummy_statement("add r1 @(r1)"), &map).unwrap(), 288423936);
+        assert_eq!(parse_instruction(&dummy_statement("store r1 @0"), &map).unwrap(), 19398656);
+        assert_eq!(parse_instruction(&dummy_statement("store r1 @(r1)"), &map).unwrap(), 19464192);
+    }
+
+    #[test]
+    fn test_parse_instruction_reports_both_operands() {
+        let map = Default::default();
+        let errors = parse_instruction(&dummy_statement("load r9 nowhere"), &map).unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].code, ErrorCode::InvalidRegister);
+        assert_eq!(errors[1].code, ErrorCode::UndefinedSymbol);
+    }
+
+    #[test]
+    fn test_parse_instruction_error_columns() {
+        let map = Default::default();
+
+        // Bad index register
+        let errors = parse_instruction(&dummy_statement("load r1, 5(r9)"), &map).unwrap_err();
+        assert_eq!(errors[0].columns, 11..13);
+        assert_eq!(errors[0].token.as_deref(), Some("r9"));
+
+        // Only the address part of the operand
+        let errors = parse_instruction(&dummy_statement("load r1, @nowhere(r2)"), &map).unwrap_err();
+        assert_eq!(errors[0].columns, 10..17);
+        assert_eq!(errors[0].token.as_deref(), Some("nowhere"));
     }
 
     fn dummy_statement(text: &str) -> Statement {
         Statement {
             statement_type: Keyword::Code,
             label: None,
-            words: text.split_whitespace().map(str::to_string).collect(),
+            words: tokenize_line(text).words,
+            file: String::new(),
             line: 0,
+            index: 0,
+            columns: 0..0,
             comment: None,
+            macro_calls: Vec::new(),
+            scope: None,
+            numeric_refs: Default::default(),
+            fold_case: false,
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,290 @@
+// SPDX-FileCopyrightText: 2024 sevonj
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! TTKTK - TTK-91 ToolKit
+//!
+//! TiToMachine k91 assembler - Conditional assembly module.
+//!
+//! "IF expr", "IFDEF name" and "IFNDEF name" start a block, which may have an "ELSE", and ends
+//! with "ENDIF". Blocks can be nested. Conditions are checked top to bottom, so they can only use
+//! constants defined above them, or given in [CompileOptions::defines](crate::compiler::CompileOptions).
+//!
+//! Conditionals are applied to source lines by the preprocessor, in the same pass that expands
+//! includes and macros. This way IF blocks decide which files get included and which macros get
+//! defined, and IF blocks in macro bodies are checked on each call.
+//!
+use std::collections::{HashMap, HashSet};
+use crate::compiler::{code_to_statements, CompileOptions, Diagnostic, ErrorCode, fold_symbol_case, is_numeric_label, Keyword, parse_const, Statement, str_to_builtin_const};
+use crate::compiler::preprocessor::SourceLine;
+
+/// An IF block that hasn't ended yet.
+struct Block {
+    /// The IF statement.
+    start: Statement,
+    /// Everything around the block is assembled.
+    outer_active: bool,
+    /// The branch we're in is assembled.
+    active: bool,
+    /// ELSE has been seen.
+    in_else: bool,
+}
+
+/// Is this a conditional assembly directive?
+pub fn is_conditional(keyword: &str) -> bool {
+    matches!(keyword, "IF" | "IFDEF" | "IFNDEF" | "ELSE" | "ENDIF")
+}
+
+/// State of conditional assembly, fed one source line at a time.
+pub struct Conditionals {
+    blocks: Vec<Block>,
+    /// Constants and symbols defined so far.
+    constants: HashMap<String, i32>,
+    symbols: HashSet<String>,
+    fold_case: bool,
+    /// Last global label, for local label names in IFDEF.
+    scope: Option<String>,
+    errors: Vec<Diagnostic>,
+}
+
+impl Conditionals {
+    pub fn new(options: &CompileOptions) -> Self {
+        let defines = options.effective_defines();
+        Conditionals {
+            blocks: Vec::new(),
+            symbols: defines.keys().cloned().collect(),
+            constants: defines,
+            fold_case: !options.symbols_case_sensitive(),
+            scope: None,
+            errors: Vec::new(),
+        }
+    }
+
+    /// Is code at this point assembled?
+    pub fn active(&self) -> bool {
+        self.blocks.last().is_none_or(|block| block.active)
+    }
+
+    /// Mark the line skipped if it's turned off, or if it's a conditional directive.
+    /// Returns false if the line is skipped.
+    pub fn apply(&mut self, source_line: &mut SourceLine) -> bool {
+        // Lines that aren't statements, like macro calls, are just on or off. Their errors are
+        // reported later.
+        let keep = match code_to_statements(std::slice::from_ref(source_line)).ok().and_then(|mut s| s.pop()) {
+            Some(mut statement) => {
+                if self.fold_case {
+                    fold_symbol_case(std::slice::from_mut(&mut statement));
+                }
+                if let Some(label) = &statement.label {
+                    let is_global = !is_numeric_label(&label.text) && !label.text.starts_with('.');
+                    if is_global && statement.statement_type != Keyword::Const && self.active() {
+                        self.scope = Some(label.text.clone());
+                    }
+                }
+                statement.scope = self.scope.clone();
+                self.read(&statement)
+            }
+            None => self.active(),
+        };
+        source_line.skip |= !keep;
+        keep
+    }
+
+    /// Take the next statement into account. Returns true if it's assembled.
+    /// Conditional directives themselves never are.
+    fn read(&mut self, statement: &Statement) -> bool {
+        let active = self.active();
+        let keyword = statement.words[0].text.to_uppercase();
+        if statement.statement_type != Keyword::Directive || !is_conditional(&keyword) {
+            if !active {
+                return false;
+            }
+            if let Some(label) = &statement.label {
+                let name = statement.qualify(&label.text).into_owned();
+                if statement.statement_type == Keyword::Const {
+                    // Broken constants are reported later.
+                    let lookup = |name: &str| str_to_builtin_const(name).ok().or_else(|| self.constants.get(name).copied());
+                    if let Ok(value) = parse_const(statement, lookup) {
+                        self.constants.insert(name.clone(), value);
+                    }
+                }
+                self.symbols.insert(name);
+            }
+            return true;
+        }
+
+        // Guard: Label
+        if statement.label.is_some() {
+            self.errors.push(statement.error(ErrorCode::LabeledDirective, format!("You can't label a compiler directive! '{}'", keyword)));
+        }
+
+        match keyword.as_str() {
+            "IF" | "IFDEF" | "IFNDEF" => {
+                // Conditions in skipped code aren't checked.
+                let condition = match active {
+                    true => match check_condition(statement, &keyword, &self.constants, &self.symbols) {
+                        Ok(condition) => condition,
+                        Err(e) => {
+                            self.errors.push(e);
+                            false
+                        }
+                    },
+                    false => false,
+                };
+                self.blocks.push(Block {
+                    start: statement.clone(),
+                    outer_active: active,
+                    active: active && condition,
+                    in_else: false,
+                });
+            }
+            "ELSE" => {
+                let Some(block) = self.blocks.last_mut() else {
+                    self.errors.push(statement.error(ErrorCode::Conditional, "Found 'ELSE' without 'IF'"));
+                    return false;
+                };
+                if block.in_else {
+                    self.errors.push(statement.error(ErrorCode::Conditional, "Found a second 'ELSE' for the same 'IF'")
+                        .with_help(format!("The 'IF' is on line {}", block.start.line)));
+                    return false;
+                }
+                block.in_else = true;
+                block.active = block.outer_active && !block.active;
+                if statement.words.len() > 1 {
+                    self.errors.push(statement.error_at(&statement.words[1], ErrorCode::TooManyValues, "Too many words for 'ELSE'"));
+                }
+            }
+            "ENDIF" => {
+                if self.blocks.pop().is_none() {
+                    self.errors.push(statement.error(ErrorCode::Conditional, "Found 'ENDIF' without 'IF'"));
+                    return false;
+                }
+                if statement.words.len() > 1 {
+                    self.errors.push(statement.error_at(&statement.words[1], ErrorCode::TooManyValues, "Too many words for 'ENDIF'"));
+                }
+            }
+            _ => unreachable!(),
+        }
+        false
+    }
+
+    /// End of input. Returns all errors found along the way.
+    pub fn finish(mut self) -> Result<(), Vec<Diagnostic>> {
+        // Guard: Unfinished blocks
+        for block in self.blocks {
+            let keyword = block.start.words[0].text.to_uppercase();
+            self.errors.push(block.start.error(ErrorCode::Conditional, format!("'{}' has no 'ENDIF'", keyword)));
+        }
+        if !self.errors.is_empty() {
+            return Err(self.errors);
+        }
+        Ok(())
+    }
+}
+
+/// Find out if the branch after IF, IFDEF or IFNDEF should be assembled.
+fn check_condition(statement: &Statement, keyword: &str, constants: &HashMap<String, i32>, symbols: &HashSet<String>) -> Result<bool, Diagnostic> {
+    // Guard: Incorrect number of words
+    match statement.words.len() {
+        2 => (), // expected amount
+        1 => return Err(statement.error(ErrorCode::MissingValue, format!("No condition given for '{}'", keyword))),
+        _ => return Err(statement.error_at(&statement.words[2], ErrorCode::TooManyValues, format!("Too many words for '{}'", keyword))),
+    }
+    let word = &statement.words[1];
+
+    match keyword {
+        "IFDEF" => Ok(symbols.contains(statement.qualify(&word.text).as_ref())),
+        "IFNDEF" => Ok(!symbols.contains(statement.qualify(&word.text).as_ref())),
+        _ => {
+            let lookup = |name: &str| str_to_builtin_const(name).ok().or_else(|| constants.get(name).copied());
+            match statement.eval_word(word, lookup) {
+                Ok(value) => Ok(value != 0),
+                Err(e) if e.code == ErrorCode::UndefinedSymbol => Err(e
+                    .with_help("Conditions can only use constants that are defined above them with EQU, or given as defines.")),
+                Err(e) => Err(e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::preprocessor::split_lines;
+
+    /// Lines of the statements that are assembled.
+    fn apply(source: &str, defines: &[(&str, i32)]) -> Result<Vec<usize>, Vec<Diagnostic>> {
+        let options = CompileOptions {
+            defines: defines.iter().map(|(name, value)| (name.to_string(), *value)).collect(),
+            ..Default::default()
+        };
+        let mut conditionals = Conditionals::new(&options);
+        let mut lines = split_lines(source, "");
+        for line in &mut lines {
+            conditionals.apply(line);
+        }
+        conditionals.finish()?;
+        Ok(code_to_statements(&lines).unwrap().iter().map(|s| s.line).collect())
+    }
+
+    #[test]
+    fn test_apply_conditionals() {
+        let source = "
+DEBUG   equ 1
+        IF DEBUG
+        out r1, =CRT
+        ELSE
+        nop
+        ENDIF
+        IF DEBUG*2 > 3
+        nop
+        ENDIF
+        IFNDEF LEVEL
+LEVEL   equ 2
+        ENDIF
+        IF LEVEL == 2
+        IFDEF nowhere
+        nop
+        ELSE
+        IF 0
+        nop
+        ENDIF
+        svc sp, =HALT
+        ENDIF
+        ENDIF";
+        assert_eq!(apply(source, &[]).unwrap(), vec![2, 4, 12, 21]);
+
+        // Defines
+        let source = "
+        IF DEBUG
+        out r1, =CRT
+        ENDIF
+        IFDEF DEBUG
+        nop
+        ENDIF";
+        assert_eq!(apply(source, &[("DEBUG", 0)]).unwrap(), vec![6]);
+        assert_eq!(apply(source, &[("DEBUG", 1)]).unwrap(), vec![3, 6]);
+    }
+
+    #[test]
+    fn test_apply_conditionals_errors() {
+        let errors = apply("IF LATER\nENDIF\nLATER equ 1", &[]).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].code, ErrorCode::UndefinedSymbol);
+        assert!(errors[0].help.is_some());
+
+        let source = "ELSE\nENDIF\nIF 1\nELSE\nELSE\nENDIF\nIF\nENDIF\nx IFDEF y\nENDIF\nIF 1";
+        let codes: Vec<ErrorCode> = apply(source, &[]).unwrap_err().iter().map(|e| e.code).collect();
+        assert_eq!(codes, vec![
+            ErrorCode::Conditional, // ELSE without IF
+            ErrorCode::Conditional, // ENDIF without IF
+            ErrorCode::Conditional, // second ELSE
+            ErrorCode::MissingValue,
+            ErrorCode::LabeledDirective,
+            ErrorCode::Conditional, // no ENDIF
+        ]);
+
+        // Skipped conditions aren't checked.
+        assert_eq!(apply("IF 0\nIF 1/0\nENDIF\nENDIF", &[]).unwrap(), Vec::<usize>::new());
+    }
+}
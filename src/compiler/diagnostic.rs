@@ -0,0 +1,245 @@
+// SPDX-FileCopyrightText: 2024 sevonj
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! TTKTK - TTK-91 ToolKit
+//!
+//! TiToMachine k91 assembler - Diagnostics module.
+//!
+use std::fmt::{Display, Formatter};
+use std::ops::Range;
+use crate::compiler::Lint;
+
+/// How bad is it?
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Severity {
+    Error,
+    /// Source compiles, but probably doesn't do what was intended.
+    Warning,
+}
+
+/// Machine-readable identifier for each kind of problem the compiler can report.
+/// The numeric values are stable, and shown as "E001" etc. in messages.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum ErrorCode {
+    /// Something the compiler should have caught earlier. Not the user's fault.
+    Internal = 0,
+    /// First word isn't an instruction, directive, or anything else we know.
+    UnknownKeyword = 1,
+    /// Register found where a keyword was expected.
+    UnexpectedRegister = 2,
+    /// Label with nothing after it.
+    MissingKeyword = 3,
+    /// Keyword needs a value, but none was given.
+    MissingValue = 4,
+    /// Keyword got more words than it takes.
+    TooManyValues = 5,
+    /// Value couldn't be parsed as a number.
+    InvalidNumber = 6,
+    /// Value doesn't fit where it's going.
+    OutOfRange = 7,
+    /// Same label is defined more than once.
+    MultipleDefinition = 8,
+    /// Directive that may only appear once appears again.
+    RepeatDirective = 9,
+    /// Directives can't have labels.
+    LabeledDirective = 10,
+    /// Constant without a name.
+    UnnamedConstant = 11,
+    /// Instruction got the wrong number of operands.
+    OperandCount = 12,
+    /// Expected a register, got something else.
+    InvalidRegister = 13,
+    /// Second operand is malformed.
+    InvalidOperand = 14,
+    /// Address is not a number, builtin, or known symbol.
+    UndefinedSymbol = 15,
+    /// Error limit was reached. Not a problem in itself.
+    TooManyErrors = 16,
+    /// Constant expression is malformed.
+    InvalidExpression = 17,
+    /// Constant expression divides by zero.
+    DivisionByZero = 18,
+    /// Symbols depend on each other in a circle.
+    CircularDefinition = 19,
+    /// Included file couldn't be read.
+    IncludeNotFound = 20,
+    /// File includes itself, directly or through other files.
+    IncludeCycle = 21,
+    /// String literal is malformed.
+    InvalidString = 22,
+    /// MACRO or ENDM is misplaced, or the macro is malformed.
+    MacroDefinition = 23,
+    /// Macro was called with the wrong number of arguments.
+    MacroArguments = 24,
+    /// Macro calls itself, directly or through other macros.
+    MacroRecursion = 25,
+    /// IF, ELSE, or ENDIF is misplaced or unmatched.
+    Conditional = 26,
+    /// Local label has no global label before it, or a numeric label reference has no label.
+    LocalLabel = 27,
+    /// Instruction isn't available in the target instruction set.
+    UnsupportedInstruction = 28,
+    /// Warning from a lint. The lint tells which one.
+    Lint = 29,
+}
+
+/// Something the compiler has to say about the source code.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub code: ErrorCode,
+    /// Source file the problem is in. Empty for the main source, if it wasn't given a name.
+    pub file: String,
+    /// Source line, starting from 1. Zero if the problem isn't tied to a line.
+    pub line: usize,
+    /// Byte range of the problem within the line.
+    pub columns: Range<usize>,
+    /// The offending piece of source code, if there is one.
+    pub token: Option<String>,
+    pub message: String,
+    /// Suggestion on how to fix the problem.
+    pub help: Option<String>,
+    /// Additional context, such as which macro call the problem came from.
+    pub notes: Vec<String>,
+    /// Lint that produced this warning. Stays set if the warning is turned into an error.
+    pub lint: Option<Lint>,
+}
+
+impl Diagnostic {
+    pub fn error(code: ErrorCode, line: usize, message: impl Into<String>) -> Self {
+        Diagnostic {
+            severity: Severity::Error,
+            code,
+            file: String::new(),
+            line,
+            columns: 0..0,
+            token: None,
+            message: message.into(),
+            help: None,
+            notes: Vec::new(),
+            lint: None,
+        }
+    }
+
+    pub fn warning(lint: Lint, line: usize, message: impl Into<String>) -> Self {
+        Diagnostic::error(ErrorCode::Lint, line, message).into_warning(lint)
+    }
+
+    /// Turn this into a warning from a lint.
+    pub fn into_warning(mut self, lint: Lint) -> Self {
+        self.severity = Severity::Warning;
+        self.code = ErrorCode::Lint;
+        self.lint = Some(lint);
+        self
+    }
+
+    pub fn with_file(mut self, file: impl Into<String>) -> Self {
+        self.file = file.into();
+        self
+    }
+
+    pub fn with_columns(mut self, columns: Range<usize>) -> Self {
+        self.columns = columns;
+        self
+    }
+
+    pub fn with_token(mut self, token: impl Into<String>) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    pub fn with_help(mut self, help: impl Into<String>) -> Self {
+        self.help = Some(help.into());
+        self
+    }
+
+    pub fn with_note(mut self, note: impl Into<String>) -> Self {
+        self.notes.push(note.into());
+        self
+    }
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+impl Display for ErrorCode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "E{:03}", *self as i32)
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.lint {
+            Some(lint) => write!(f, "{}[{}]: ", self.severity, lint)?,
+            None => write!(f, "{}[{}]: ", self.severity, self.code)?,
+        }
+        match (self.file.is_empty(), self.line) {
+            (true, 0) => (),
+            (true, line) => write!(f, "Line {}: ", line)?,
+            (false, 0) => write!(f, "{}: ", self.file)?,
+            (false, line) => write!(f, "{}, line {}: ", self.file, line)?,
+        }
+        write!(f, "{}", self.message)?;
+        for note in &self.notes {
+            write!(f, "\n    note: {note}")?;
+        }
+        if let Some(help) = &self.help {
+            write!(f, "\n    help: {help}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diagnostic_display() {
+        let diagnostic = Diagnostic::error(ErrorCode::UndefinedSymbol, 5, "Invalid address: 'foo'")
+            .with_token("foo")
+            .with_help("Define it with EQU, DC, or DS.");
+        assert_eq!(
+            diagnostic.to_string(),
+            "error[E015]: Line 5: Invalid address: 'foo'\n    help: Define it with EQU, DC, or DS."
+        );
+    }
+
+    #[test]
+    fn test_diagnostic_display_no_line() {
+        let diagnostic = Diagnostic::error(ErrorCode::Internal, 0, "oops");
+        assert_eq!(diagnostic.to_string(), "error[E000]: oops");
+    }
+
+    #[test]
+    fn test_diagnostic_display_notes() {
+        let diagnostic = Diagnostic::error(ErrorCode::InvalidRegister, 2, "R9 is not a register.")
+            .with_note("In macro 'PRINT', called from line 10")
+            .with_help("Registers are R0-R7, SP, and FP.");
+        assert_eq!(
+            diagnostic.to_string(),
+            "error[E013]: Line 2: R9 is not a register.\n    note: In macro 'PRINT', called from line 10\n    help: Registers are R0-R7, SP, and FP."
+        );
+    }
+
+    #[test]
+    fn test_diagnostic_display_file() {
+        let diagnostic = Diagnostic::error(ErrorCode::UnknownKeyword, 3, "Unknown keyword 'FOO'").with_file("io.k91");
+        assert_eq!(diagnostic.to_string(), "error[E001]: io.k91, line 3: Unknown keyword 'FOO'");
+    }
+
+    #[test]
+    fn test_diagnostic_display_warning() {
+        let diagnostic = Diagnostic::warning(Lint::UnusedLabel, 4, "Label 'foo' is never used");
+        assert_eq!(diagnostic.code, ErrorCode::Lint);
+        assert_eq!(diagnostic.to_string(), "warning[unused-label]: Line 4: Label 'foo' is never used");
+    }
+}
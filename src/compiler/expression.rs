@@ -0,0 +1,330 @@
+// SPDX-FileCopyrightText: 2024 sevonj
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! TTKTK - TTK-91 ToolKit
+//!
+//! TiToMachine k91 assembler - Constant expression module.
+//!
+use std::ops::Range;
+use crate::compiler::{ErrorCode, is_numeric_reference, str_to_integer};
+
+/// Characters that end a number or a symbol name.
+pub const OPERATOR_CHARS: [char; 14] = ['+', '-', '*', '/', '%', '<', '>', '=', '!', '&', '|', '^', '~', '('];
+
+/// Something wrong with an expression.
+#[derive(Debug)]
+pub struct ExprError {
+    pub code: ErrorCode,
+    pub message: String,
+    /// Where the problem is within the expression.
+    pub columns: Range<usize>,
+}
+
+impl ExprError {
+    fn new(code: ErrorCode, message: impl Into<String>, columns: Range<usize>) -> Self {
+        ExprError {
+            code,
+            message: message.into(),
+            columns,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+enum ExprToken {
+    Number(i64),
+    Symbol(String),
+    Operator(&'static str),
+    Open,
+    Close,
+}
+
+/// Evaluate a constant expression, such as "BUFSIZE-1" or "(table+4)*2".
+/// Symbols are looked up with `lookup`. Numbers are read like everywhere else, so "0xffffffff" is
+/// still -1. Error columns are relative to the start of `text`.
+///
+/// Operators, from lowest to highest precedence: `== != < <= > >=`, `|`, `^`, `&`, `<< >>`,
+/// `+ -`, `* / %`, and unary `- + ~`. Comparisons give 1 for true and 0 for false.
+pub fn eval_expression(text: &str, lookup: impl Fn(&str) -> Option<i32>) -> Result<i64, ExprError> {
+    let tokens = tokenize_expression(text)?;
+    if tokens.is_empty() {
+        return Err(ExprError::new(ErrorCode::InvalidExpression, "Empty expression", 0..text.len()));
+    }
+    let mut parser = Parser {
+        tokens: &tokens,
+        pos: 0,
+        end: text.len(),
+        lookup: &lookup,
+    };
+    let value = parser.parse_binary(0)?;
+
+    // Guard: Leftovers
+    if let Some((token, columns)) = parser.tokens.get(parser.pos) {
+        let message = match token {
+            ExprToken::Close => "Unmatched ')'".to_string(),
+            _ => format!("Unexpected '{}'", &text[columns.clone()]),
+        };
+        return Err(ExprError::new(ErrorCode::InvalidExpression, message, columns.clone()));
+    }
+    Ok(value)
+}
+
+/// Names of the symbols an expression refers to, in order. Builtins are included.
+/// If the expression can't be read, returns what was found before the problem.
+pub fn expression_symbols(text: &str) -> Vec<String> {
+    let mut symbols = Vec::new();
+    for (token, _) in tokenize_expression(text).unwrap_or_default() {
+        if let ExprToken::Symbol(name) = token {
+            symbols.push(name);
+        }
+    }
+    symbols
+}
+
+/// Split an expression into numbers, symbols, operators, and parentheses.
+fn tokenize_expression(text: &str) -> Result<Vec<(ExprToken, Range<usize>)>, ExprError> {
+    let mut tokens = Vec::new();
+    let mut chars = text.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        if c.is_whitespace() {
+            continue;
+        }
+        let token = match c {
+            '(' => ExprToken::Open,
+            ')' => ExprToken::Close,
+            '+' => ExprToken::Operator("+"),
+            '-' => ExprToken::Operator("-"),
+            '*' => ExprToken::Operator("*"),
+            '/' => ExprToken::Operator("/"),
+            '%' => ExprToken::Operator("%"),
+            '&' => ExprToken::Operator("&"),
+            '|' => ExprToken::Operator("|"),
+            '^' => ExprToken::Operator("^"),
+            '~' => ExprToken::Operator("~"),
+            '<' | '>' | '=' | '!' => {
+                let next = chars.peek().map(|&(_, next)| next);
+                let operator = match (c, next) {
+                    ('<', Some('<')) => "<<",
+                    ('>', Some('>')) => ">>",
+                    ('<', Some('=')) => "<=",
+                    ('>', Some('=')) => ">=",
+                    ('=', Some('=')) => "==",
+                    ('!', Some('=')) => "!=",
+                    ('<', _) => "<",
+                    ('>', _) => ">",
+                    _ => return Err(ExprError::new(ErrorCode::InvalidExpression, format!("Unknown operator '{}'", c), i..i + 1)),
+                };
+                if operator.len() == 2 {
+                    chars.next();
+                }
+                tokens.push((ExprToken::Operator(operator), i..i + operator.len()));
+                continue;
+            }
+            _ => {
+                // Number or symbol: everything up to the next operator or space.
+                let mut end = i + c.len_utf8();
+                while let Some(&(j, next)) = chars.peek() {
+                    if next.is_whitespace() || next == ')' || OPERATOR_CHARS.contains(&next) {
+                        break;
+                    }
+                    end = j + next.len_utf8();
+                    chars.next();
+                }
+                let word = &text[i..end];
+                // Numeric label references ("1b", "1f") start with a digit, but they're symbols.
+                let token = if c.is_ascii_digit() && !is_numeric_reference(word) {
+                    match str_to_integer(word) {
+                        Ok(value) => ExprToken::Number(value as i64),
+                        Err(e) => return Err(ExprError::new(ErrorCode::InvalidNumber, e, i..end)),
+                    }
+                } else {
+                    ExprToken::Symbol(word.to_string())
+                };
+                tokens.push((token, i..end));
+                continue;
+            }
+        };
+        tokens.push((token, i..i + c.len_utf8()));
+    }
+    Ok(tokens)
+}
+
+/// Precedence of a binary operator. Higher binds tighter.
+fn binary_precedence(operator: &str) -> Option<u8> {
+    match operator {
+        "==" | "!=" | "<" | "<=" | ">" | ">=" => Some(1),
+        "|" => Some(2),
+        "^" => Some(3),
+        "&" => Some(4),
+        "<<" | ">>" => Some(5),
+        "+" | "-" => Some(6),
+        "*" | "/" | "%" => Some(7),
+        _ => None,
+    }
+}
+
+/// Precedence climbing parser that evaluates as it goes.
+struct Parser<'a, F: Fn(&str) -> Option<i32>> {
+    tokens: &'a [(ExprToken, Range<usize>)],
+    pos: usize,
+    /// Length of the expression, for errors at the end.
+    end: usize,
+    lookup: &'a F,
+}
+
+impl<F: Fn(&str) -> Option<i32>> Parser<'_, F> {
+    /// Parse binary operators that bind tighter than `min_precedence`.
+    fn parse_binary(&mut self, min_precedence: u8) -> Result<i64, ExprError> {
+        let mut lhs = self.parse_unary()?;
+        while let Some((ExprToken::Operator(operator), columns)) = self.tokens.get(self.pos) {
+            let Some(precedence) = binary_precedence(operator) else {
+                break;
+            };
+            if precedence <= min_precedence {
+                break;
+            }
+            self.pos += 1;
+            let rhs = self.parse_binary(precedence)?;
+            lhs = apply_binary(operator, lhs, rhs, columns.clone())?;
+        }
+        Ok(lhs)
+    }
+
+    fn parse_unary(&mut self) -> Result<i64, ExprError> {
+        let Some((token, columns)) = self.tokens.get(self.pos) else {
+            return Err(ExprError::new(ErrorCode::InvalidExpression, "Expression ends too early", self.end..self.end));
+        };
+        self.pos += 1;
+        match token {
+            ExprToken::Number(value) => Ok(*value),
+            ExprToken::Symbol(name) => match (self.lookup)(name) {
+                Some(value) => Ok(value as i64),
+                None => Err(ExprError::new(ErrorCode::UndefinedSymbol, format!("Undefined symbol: '{}'", name), columns.clone())),
+            },
+            ExprToken::Operator("-") => self.parse_unary()?.checked_neg()
+                .ok_or_else(|| ExprError::new(ErrorCode::OutOfRange, "Value is too large", columns.clone())),
+            ExprToken::Operator("+") => self.parse_unary(),
+            ExprToken::Operator("~") => Ok(!self.parse_unary()?),
+            ExprToken::Operator(operator) => Err(ExprError::new(ErrorCode::InvalidExpression, format!("Expected a value before '{}'", operator), columns.clone())),
+            ExprToken::Open => {
+                let value = self.parse_binary(0)?;
+                match self.tokens.get(self.pos) {
+                    Some((ExprToken::Close, _)) => {
+                        self.pos += 1;
+                        Ok(value)
+                    }
+                    _ => Err(ExprError::new(ErrorCode::InvalidExpression, "Unclosed parentheses", columns.start..self.end)),
+                }
+            }
+            ExprToken::Close => Err(ExprError::new(ErrorCode::InvalidExpression, "Expected a value before ')'", columns.clone())),
+        }
+    }
+}
+
+fn apply_binary(operator: &str, lhs: i64, rhs: i64, columns: Range<usize>) -> Result<i64, ExprError> {
+    let result = match operator {
+        "+" => lhs.checked_add(rhs),
+        "-" => lhs.checked_sub(rhs),
+        "*" => lhs.checked_mul(rhs),
+        "/" | "%" => {
+            // Guard: Division by zero
+            if rhs == 0 {
+                return Err(ExprError::new(ErrorCode::DivisionByZero, "Division by zero", columns));
+            }
+            match operator {
+                "/" => lhs.checked_div(rhs),
+                _ => lhs.checked_rem(rhs),
+            }
+        }
+        "<<" | ">>" => {
+            // Guard: Shift amount
+            if !(0..32).contains(&rhs) {
+                return Err(ExprError::new(ErrorCode::OutOfRange, format!("Can't shift by {}", rhs), columns));
+            }
+            match operator {
+                "<<" => lhs.checked_shl(rhs as u32),
+                _ => lhs.checked_shr(rhs as u32),
+            }
+        }
+        "&" => Some(lhs & rhs),
+        "|" => Some(lhs | rhs),
+        "^" => Some(lhs ^ rhs),
+        "==" => Some((lhs == rhs) as i64),
+        "!=" => Some((lhs != rhs) as i64),
+        "<" => Some((lhs < rhs) as i64),
+        "<=" => Some((lhs <= rhs) as i64),
+        ">" => Some((lhs > rhs) as i64),
+        ">=" => Some((lhs >= rhs) as i64),
+        _ => None,
+    };
+    result.ok_or_else(|| ExprError::new(ErrorCode::OutOfRange, "Value is too large", columns))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(text: &str) -> Result<i64, ExprError> {
+        eval_expression(text, |name| match name {
+            "BUFSIZE" => Some(16),
+            "table" => Some(100),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn test_eval_expression_precedence() {
+        assert_eq!(eval("1+2*3").unwrap(), 7);
+        assert_eq!(eval("(1+2)*3").unwrap(), 9);
+        assert_eq!(eval("10-4-3").unwrap(), 3);
+        assert_eq!(eval("1<<4+1").unwrap(), 32);
+        assert_eq!(eval("6&3|8").unwrap(), 10);
+        assert_eq!(eval("6^3&1").unwrap(), 7);
+        assert_eq!(eval("-7/2").unwrap(), -3);
+        assert_eq!(eval("-7%2").unwrap(), -1);
+        assert_eq!(eval("~0").unwrap(), -1);
+        assert_eq!(eval("-(2+3)").unwrap(), -5);
+        assert_eq!(eval("0x10>>2").unwrap(), 4);
+        assert_eq!(eval("( 1 + 2 ) * 3").unwrap(), 9);
+        assert_eq!(eval("1+1==2").unwrap(), 1);
+        assert_eq!(eval("2<1|2").unwrap(), 1);
+        assert_eq!(eval("-1<=0").unwrap(), 1);
+        assert_eq!(eval("3>3").unwrap(), 0);
+        assert_eq!(eval("3>=3").unwrap(), 1);
+        assert_eq!(eval("1!=1").unwrap(), 0);
+    }
+
+    #[test]
+    fn test_eval_expression_symbols() {
+        assert_eq!(eval("BUFSIZE-1").unwrap(), 15);
+        assert_eq!(eval("table+BUFSIZE*2").unwrap(), 132);
+
+        let e = eval("table+nowhere").unwrap_err();
+        assert_eq!(e.code, ErrorCode::UndefinedSymbol);
+        assert_eq!(e.columns, 6..13);
+    }
+
+    #[test]
+    fn test_expression_symbols() {
+        assert_eq!(expression_symbols("(a+b)*2-a"), vec!["a", "b", "a"]);
+        assert!(expression_symbols("1+2").is_empty());
+        assert_eq!(expression_symbols("1b+0x1b+2f"), vec!["1b", "2f"]);
+    }
+
+    #[test]
+    fn test_eval_expression_errors() {
+        assert_eq!(eval("").unwrap_err().code, ErrorCode::InvalidExpression);
+        assert_eq!(eval("1+").unwrap_err().code, ErrorCode::InvalidExpression);
+        assert_eq!(eval("(1+2").unwrap_err().code, ErrorCode::InvalidExpression);
+        assert_eq!(eval("1+2)").unwrap_err().code, ErrorCode::InvalidExpression);
+        assert_eq!(eval("1 2").unwrap_err().code, ErrorCode::InvalidExpression);
+        assert_eq!(eval("1=2").unwrap_err().code, ErrorCode::InvalidExpression);
+        assert_eq!(eval("!1").unwrap_err().code, ErrorCode::InvalidExpression);
+        assert_eq!(eval("*2").unwrap_err().code, ErrorCode::InvalidExpression);
+        assert_eq!(eval("0xfg").unwrap_err().code, ErrorCode::InvalidNumber);
+        assert_eq!(eval("1/0").unwrap_err().code, ErrorCode::DivisionByZero);
+        assert_eq!(eval("1%(2-2)").unwrap_err().columns, 1..2);
+        assert_eq!(eval("1<<32").unwrap_err().code, ErrorCode::OutOfRange);
+    }
+}
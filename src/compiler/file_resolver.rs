@@ -0,0 +1,85 @@
+// SPDX-FileCopyrightText: 2024 sevonj
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! TTKTK - TTK-91 ToolKit
+//!
+//! TiToMachine k91 assembler - File resolver module. Supplies files for INCLUDE.
+//!
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+/// Finds and reads files for the INCLUDE directive.
+/// Implement this to include files from somewhere other than the filesystem.
+pub trait FileResolver: Send + Sync {
+    /// Read the file `path`, which is included from the file `from`.
+    /// Returns the name of the file and its contents. The name is what diagnostics show, what
+    /// nested includes see as `from`, and what include cycles are detected by, so the same file
+    /// should always get the same name.
+    fn resolve(&self, path: &str, from: &str) -> Result<(String, String), String>;
+}
+
+/// Reads included files from the filesystem. Paths are relative to the including file.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct FsResolver;
+
+impl FileResolver for FsResolver {
+    fn resolve(&self, path: &str, from: &str) -> Result<(String, String), String> {
+        let directory = Path::new(from).parent().unwrap_or(Path::new(""));
+        let name = normalize_path(&directory.join(path));
+        match fs::read_to_string(&name) {
+            Ok(contents) => Ok((name.to_string_lossy().into_owned(), contents)),
+            Err(e) => Err(format!("Can't read '{}': {}", name.display(), e)),
+        }
+    }
+}
+
+/// In-memory files by name. Paths are used as-is.
+impl FileResolver for HashMap<String, String> {
+    fn resolve(&self, path: &str, _from: &str) -> Result<(String, String), String> {
+        match self.get(path) {
+            Some(contents) => Ok((path.to_string(), contents.clone())),
+            None => Err(format!("No such file: '{}'", path)),
+        }
+    }
+}
+
+/// Remove "." and "dir/.." from a path without touching the filesystem, so that the same file gets
+/// the same name no matter how it was reached.
+fn normalize_path(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir => match normalized.components().next_back() {
+                Some(Component::Normal(_)) => {
+                    normalized.pop();
+                }
+                _ => normalized.push(".."),
+            },
+            other => normalized.push(other),
+        }
+    }
+    normalized
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_path() {
+        assert_eq!(normalize_path(Path::new("./a.k91")), PathBuf::from("a.k91"));
+        assert_eq!(normalize_path(Path::new("lib/../a.k91")), PathBuf::from("a.k91"));
+        assert_eq!(normalize_path(Path::new("lib/./io/x.k91")), PathBuf::from("lib/io/x.k91"));
+        assert_eq!(normalize_path(Path::new("../x.k91")), PathBuf::from("../x.k91"));
+    }
+
+    #[test]
+    fn test_hashmap_resolver() {
+        let files = HashMap::from([("io.k91".to_string(), "nop".to_string())]);
+        assert_eq!(files.resolve("io.k91", "main.k91").unwrap(), ("io.k91".to_string(), "nop".to_string()));
+        assert!(files.resolve("nope.k91", "main.k91").is_err());
+    }
+}
@@ -0,0 +1,310 @@
+// SPDX-FileCopyrightText: 2024 sevonj
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! TTKTK - TTK-91 ToolKit
+//!
+//! TiToMachine k91 assembler - Lint module. Finds code that compiles, but is probably wrong.
+//!
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use crate::compiler::{Diagnostic, ErrorCode, Keyword, Statement, Symbol, SymbolType, word_names};
+use crate::compiler::expression::expression_symbols;
+use crate::instructions::Register;
+
+/// A kind of warning. Each one can be turned on and off with [CompileOptions::lints](crate::compiler::CompileOptions::lints).
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum Lint {
+    /// Code or DC label that nothing refers to.
+    UnusedLabel,
+    /// Code right after JUMP, EXIT, HLT or similar, without a label that could lead to it.
+    UnreachableCode,
+    /// STORE to a literal address, like "STORE R1, 100". It probably should have been a variable.
+    StoreToLiteralAddress,
+    /// SP or FP is changed outside of a subroutine's prologue or epilogue.
+    StackPointerWrite,
+    /// DS that nothing refers to.
+    UnusedData,
+    /// Jump or call to a data label.
+    JumpToData,
+}
+
+impl Lint {
+    /// Every lint there is.
+    pub const ALL: [Lint; 6] = [
+        Lint::UnusedLabel,
+        Lint::UnreachableCode,
+        Lint::StoreToLiteralAddress,
+        Lint::StackPointerWrite,
+        Lint::UnusedData,
+        Lint::JumpToData,
+    ];
+
+    /// Name of the lint, as used in `-W name`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Lint::UnusedLabel => "unused-label",
+            Lint::UnreachableCode => "unreachable-code",
+            Lint::StoreToLiteralAddress => "store-to-literal-address",
+            Lint::StackPointerWrite => "stack-pointer-write",
+            Lint::UnusedData => "unused-data",
+            Lint::JumpToData => "jump-to-data",
+        }
+    }
+}
+
+impl Display for Lint {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for Lint {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Lint::ALL.iter()
+            .find(|lint| lint.name() == s)
+            .copied()
+            .ok_or_else(|| format!("Unknown lint: '{}'", s))
+    }
+}
+
+/// Instructions that write to the register in their first operand.
+const REGISTER_WRITES: [&str; 14] = ["LOAD", "IN", "ADD", "SUB", "MUL", "DIV", "MOD", "AND", "OR", "XOR", "SHL", "SHR", "NOT", "SHRA"];
+/// Instructions that jump to their address operand.
+const JUMPS: [&str; 14] = ["JUMP", "JNEG", "JZER", "JPOS", "JNNEG", "JNZER", "JNPOS", "JLES", "JEQU", "JGRE", "JNLES", "JNEQU", "JNGRE", "CALL"];
+/// Instructions that never continue to the next one.
+const NO_RETURN: [&str; 5] = ["JUMP", "EXIT", "IEXIT", "HLT", "HCF"];
+
+/// Run the enabled lints. Warnings come out in source order.
+pub fn run_lints(statements: &[Statement], symbol_table: &HashMap<String, Symbol>, enabled: &HashSet<Lint>) -> Vec<Diagnostic> {
+    let mut warnings: Vec<(usize, Diagnostic)> = Vec::new();
+    let mut warn = |lint: Lint, statement: &Statement, diagnostic: Diagnostic| {
+        if enabled.contains(&lint) {
+            warnings.push((statement.index, diagnostic.into_warning(lint)));
+        }
+    };
+
+    // Symbols used anywhere
+    let mut references: HashSet<String> = HashSet::new();
+    for statement in statements {
+        for word in statement.words.iter().skip(1) {
+            references.extend(word_names(&word.text).map(|name| statement.qualify(name).into_owned()));
+        }
+    }
+
+    let code: Vec<&Statement> = statements.iter().filter(|s| s.statement_type == Keyword::Code).collect();
+    let allowed_stack_writes = find_prologues_and_epilogues(&code);
+
+    for (i, statement) in code.iter().enumerate() {
+        let keyword = statement.words[0].text.to_uppercase();
+        let keyword = keyword.as_str();
+        let address = statement.words.last().filter(|_| statement.words.len() > 1);
+
+        // Unused label. The first instruction is the entry point, so it doesn't need references.
+        if let Some(label) = &statement.label {
+            if i > 0 && !references.contains(&label.text) && !is_generated_label(&label.text) {
+                warn(Lint::UnusedLabel, statement, statement.error_at(label, ErrorCode::Lint, format!("Label '{}' is never used", label.text)));
+            }
+        }
+
+        // Unreachable code
+        if i > 0 && statement.label.is_none() {
+            let previous = code[i - 1];
+            let previous_keyword = previous.words[0].text.to_uppercase();
+            if ends_flow(previous) && (i < 2 || !ends_flow(code[i - 2]) || code[i - 1].label.is_some()) {
+                warn(Lint::UnreachableCode, statement, statement.error(ErrorCode::Lint, format!("Unreachable code after '{}'", previous_keyword))
+                    .with_help("Nothing jumps here. Add a label if something should."));
+            }
+        }
+
+        // STORE to a number
+        if keyword == "STORE" {
+            if let Some(address) = address {
+                let text = address.text.as_str();
+                let is_literal = !text.starts_with(['@', '=']) && !text.contains('(') && expression_symbols(text).is_empty();
+                if is_literal {
+                    warn(Lint::StoreToLiteralAddress, statement, statement.error_at(address, ErrorCode::Lint, format!("STORE to memory address {}", text))
+                        .with_help("STORE can't take an immediate value. To store into a variable, use its name."));
+                }
+            }
+        }
+
+        // SP or FP write
+        let destination = match keyword {
+            "POP" => statement.words.get(2),
+            _ if REGISTER_WRITES.contains(&keyword) => statement.words.get(1),
+            _ => None,
+        };
+        if let Some(destination) = destination {
+            let register = Register::from_str(&destination.text);
+            let is_stack_register = matches!(register, Ok(Register::R6) | Ok(Register::R7));
+            if is_stack_register && !allowed_stack_writes.contains(&i) {
+                warn(Lint::StackPointerWrite, statement, statement.error_at(destination, ErrorCode::Lint, format!("'{}' writes to {}", keyword, destination.text.to_uppercase()))
+                    .with_help("SP and FP are usually only changed at the start and end of a subroutine."));
+            }
+        }
+
+        // Jump to data
+        if JUMPS.contains(&keyword) {
+            if let Some(address) = address.filter(|address| !address.text.starts_with(['@', '='])) {
+                for name in word_names(&address.text) {
+                    let name = statement.qualify(name);
+                    if symbol_table.get(name.as_ref()).is_some_and(|symbol| symbol.symbol_type == SymbolType::Data) {
+                        warn(Lint::JumpToData, statement, statement.error_at(address, ErrorCode::Lint, format!("'{}' jumps to data label '{}'", keyword, name)));
+                    }
+                }
+            }
+        }
+    }
+
+    for statement in statements.iter().filter(|s| s.statement_type == Keyword::Data) {
+        let keyword = statement.words[0].text.to_uppercase();
+        match (&statement.label, keyword.as_str()) {
+            // Unused DS
+            (None, "DS") => warn(Lint::UnusedData, statement, statement.error(ErrorCode::Lint, "DS without a label can't be used")),
+            (Some(label), "DS") if !references.contains(&label.text) => {
+                warn(Lint::UnusedData, statement, statement.error_at(label, ErrorCode::Lint, format!("'{}' is never used", label.text)));
+            }
+            // Unused DC or string
+            (Some(label), _) if !references.contains(&label.text) && !is_generated_label(&label.text) => {
+                warn(Lint::UnusedLabel, statement, statement.error_at(label, ErrorCode::Lint, format!("Label '{}' is never used", label.text)));
+            }
+            _ => (),
+        }
+    }
+
+    warnings.sort_by_key(|(index, _)| *index);
+    warnings.into_iter().map(|(_, warning)| warning).collect()
+}
+
+/// Does the program never continue from this instruction to the next one?
+fn ends_flow(statement: &Statement) -> bool {
+    let keyword = statement.words[0].text.to_uppercase();
+    let halts = keyword == "SVC" && statement.words.last().is_some_and(|word| statement.qualify(&word.text).to_uppercase() == "=HALT");
+    NO_RETURN.contains(&keyword.as_str()) || halts
+}
+
+/// Labels the compiler made up: numeric labels ("_1_2") and labels from macros ("loop#1").
+fn is_generated_label(label: &str) -> bool {
+    let is_number = |text: &str| !text.is_empty() && text.chars().all(|c| c.is_ascii_digit());
+    let is_numeric = label.strip_prefix('_')
+        .and_then(|rest| rest.split_once('_'))
+        .is_some_and(|(number, count)| is_number(number) && is_number(count));
+    is_numeric || label.contains('#')
+}
+
+/// Find the instructions that may change SP and FP: the ones at the start of a subroutine, after a
+/// global label, and the ones at its end, before EXIT. Returns indices to `code`.
+fn find_prologues_and_epilogues(code: &[&Statement]) -> HashSet<usize> {
+    let is_stack_setup = |statement: &Statement, stack_ops: [&str; 2]| {
+        let keyword = statement.words[0].text.to_uppercase();
+        let destination = match keyword.as_str() {
+            "POP" => statement.words.get(2),
+            _ => statement.words.get(1),
+        };
+        let writes_stack = REGISTER_WRITES.contains(&keyword.as_str()) || keyword == "POP";
+        let is_stack_register = destination.is_some_and(|word| matches!(Register::from_str(&word.text), Ok(Register::R6) | Ok(Register::R7)));
+        stack_ops.contains(&keyword.as_str()) || (writes_stack && is_stack_register)
+    };
+
+    let mut allowed = HashSet::new();
+    for (i, statement) in code.iter().enumerate() {
+        // Prologue: after a global label
+        let is_global = statement.label.as_ref()
+            .is_some_and(|label| !label.text.contains('.') && !is_generated_label(&label.text));
+        if is_global {
+            for (j, next) in code.iter().enumerate().skip(i) {
+                if j > i && next.label.is_some() || !is_stack_setup(next, ["PUSH", "PUSHR"]) {
+                    break;
+                }
+                allowed.insert(j);
+            }
+        }
+
+        // Epilogue: before EXIT
+        let keyword = statement.words[0].text.to_uppercase();
+        if keyword == "EXIT" || keyword == "IEXIT" {
+            for j in (0..i).rev() {
+                if !is_stack_setup(code[j], ["POP", "POPR"]) {
+                    break;
+                }
+                allowed.insert(j);
+                if code[j].label.is_some() {
+                    break;
+                }
+            }
+        }
+    }
+    allowed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::{compile_full, CompileOptions, Severity};
+
+    fn warnings(source: &str) -> Vec<(Lint, usize)> {
+        let warnings = compile_full(source.to_string(), &CompileOptions::default()).unwrap().warnings;
+        assert!(warnings.iter().all(|w| w.severity == Severity::Warning));
+        warnings.iter().map(|w| (w.lint.unwrap(), w.line)).collect()
+    }
+
+    #[test]
+    fn test_lint_names() {
+        for lint in Lint::ALL {
+            assert_eq!(Lint::from_str(lint.name()), Ok(lint));
+        }
+        assert!(Lint::from_str("nope").is_err());
+    }
+
+    #[test]
+    fn test_lint_clean_program() {
+        let source = "
+        x       dc 5
+        buf     ds 2
+        main    load r1, x
+                store r1, buf
+                call sp, f
+                svc sp, =HALT
+        f       push sp, fp
+                load fp, sp
+                load r1, =1(fp)
+                load sp, fp
+                pop sp, fp
+                exit sp, =0";
+        assert_eq!(warnings(source), vec![]);
+    }
+
+    #[test]
+    fn test_lints() {
+        let source = "
+        x       dc 5
+        unused  dc 1
+                ds 2
+        buf     ds 2
+        main    load r1, x
+                store r1, 100
+                jump x
+                nop
+                nop
+        other   add sp, =1
+                jzer r1, @x
+                svc sp, =HALT
+                nop";
+        assert_eq!(warnings(source), vec![
+            (Lint::UnusedLabel, 3),
+            (Lint::UnusedData, 4),
+            (Lint::UnusedData, 5),
+            (Lint::StoreToLiteralAddress, 7),
+            (Lint::JumpToData, 8),
+            (Lint::UnreachableCode, 9),
+            (Lint::UnusedLabel, 11),
+            (Lint::UnreachableCode, 14),
+        ]);
+
+        // Prologue of "other" allows the SP write, but not elsewhere.
+        assert_eq!(warnings("main nop\nadd sp, =1\nsvc sp, =HALT"), vec![(Lint::StackPointerWrite, 2)]);
+    }
+}
@@ -0,0 +1,149 @@
+// SPDX-FileCopyrightText: 2024 sevonj
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! TTKTK - TTK-91 ToolKit
+//!
+//! TiToMachine k91 assembler - Listing module.
+//!
+use std::collections::HashMap;
+use std::fmt::Write;
+use crate::compiler::{Assembly, Placement};
+use crate::disassembler::disassemble_instruction;
+
+/// Width of the columns before source line number. Used to line up source lines that don't take
+/// up memory with the ones that do.
+const ADDRESS_COLUMNS_WIDTH: usize = 5 + 2 + 8 + 2 + 11 + 2 + 20;
+
+/// Create a listing: every source line, next to the address and contents it was compiled into.
+/// Symbol table follows after. Included files are listed where they were included, with a header
+/// line whenever the file changes. Lines expanded from macros have a '+' after the line number.
+pub fn create_listing(assembly: &Assembly, main_file: &str) -> String {
+    let mut listing = String::new();
+
+    // Placements by source line
+    let mut placements: HashMap<usize, Vec<&Placement>> = HashMap::new();
+    for placement in &assembly.placements {
+        placements.entry(placement.index).or_default().push(placement);
+    }
+
+    // --- Source
+    let _ = writeln!(listing, "{:>5}  {:<8}  {:>11}  {:<20}  {:>5}  Source", "Addr", "Hex", "Decimal", "Instruction", "Line");
+    let mut current_file = main_file;
+    for (index, source_line) in assembly.lines.iter().enumerate() {
+        let line = match source_line.macro_calls.is_empty() {
+            true => source_line.line.to_string(),
+            false => format!("{}+", source_line.line),
+        };
+        let text = &source_line.text;
+        if source_line.file != current_file {
+            current_file = &source_line.file;
+            let _ = writeln!(listing, "{:ADDRESS_COLUMNS_WIDTH$}  {:>5}  ==> {} <==", "", "", current_file);
+        }
+        let Some(line_placements) = placements.get(&index) else {
+            let row = format!("{:ADDRESS_COLUMNS_WIDTH$}  {:>5}  {}", "", line, text);
+            let _ = writeln!(listing, "{}", row.trim_end());
+            continue;
+        };
+        for placement in line_placements {
+            for (offset, value) in placement.content.iter().enumerate() {
+                let instruction = match placement.is_code {
+                    true => disassemble_instruction(*value),
+                    false => String::new(),
+                };
+                let mut row = format!("{:>5}  {:08X}  {:>11}  {:<20}", placement.address + offset, value, value, instruction);
+                // Source is only shown once, even if the statement takes up many addresses.
+                if offset == 0 {
+                    row += format!("  {:>5}  {}", line, text).as_str();
+                }
+                let _ = writeln!(listing, "{}", row.trim_end());
+            }
+        }
+    }
+
+    // --- Symbol table
+    let mut symbols: Vec<_> = assembly.symbol_table.iter().collect();
+    symbols.sort_by(|a, b| a.0.cmp(b.0));
+    let name_width = symbols.iter().map(|(name, _)| name.len()).max().unwrap_or(0).max(6);
+
+    let _ = writeln!(listing);
+    let _ = writeln!(listing, "Symbol table:");
+    let _ = writeln!(listing, "{:<name_width$}  {:>11}  Type", "Symbol", "Value");
+    for (name, symbol) in symbols {
+        let _ = writeln!(listing, "{:<name_width$}  {:>11}  {:?}", name, symbol.offset, symbol.symbol_type);
+    }
+    listing
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use crate::compiler::{compile_full, CompileOptions};
+
+    #[test]
+    fn test_create_listing() {
+        let source = "x dc -1 ; data
+y ds 2
+c equ 5
+
+main load r1, x ; code
+svc sp, =HALT";
+        let listing = compile_full(source.to_string(), &CompileOptions::default()).unwrap().listing;
+        let lines: Vec<&str> = listing.lines().collect();
+
+        assert_eq!(lines[0], " Addr  Hex           Decimal  Instruction            Line  Source");
+        assert_eq!(lines[1], "    2  FFFFFFFF           -1                            1  x dc -1 ; data");
+        assert_eq!(lines[2], "    3  00000000            0                            2  y ds 2");
+        assert_eq!(lines[3], "    4  00000000            0");
+        assert_eq!(lines[4], "                                                        3  c equ 5");
+        assert_eq!(lines[5], "                                                        4");
+        assert_eq!(lines[6], "    0  02280002     36175874  LOAD  R1,  2              5  main load r1, x ; code");
+        assert_eq!(lines[7], "    1  70C0000B   1891631115  SVC   SP, =11             6  svc sp, =HALT");
+        assert_eq!(lines[8], "");
+        assert_eq!(lines[9], "Symbol table:");
+        assert_eq!(lines[10], "Symbol        Value  Type");
+        assert_eq!(lines[11], "c                 5  Const");
+        assert_eq!(lines[12], "main              0  Code");
+        assert_eq!(lines[13], "x                 2  Data");
+        assert_eq!(lines[14], "y                 3  Data");
+        assert_eq!(lines.len(), 15);
+    }
+
+    #[test]
+    fn test_create_listing_include() {
+        let files = HashMap::from([("io.k91".to_string(), "out r1, =CRT".to_string())]);
+        let options = CompileOptions {
+            source_name: "main.k91".into(),
+            file_resolver: Some(Arc::new(files)),
+            ..Default::default()
+        };
+        let source = "load r1, =1
+INCLUDE \"io.k91\"
+svc sp, =HALT";
+        let listing = compile_full(source.to_string(), &options).unwrap().listing;
+        let lines: Vec<&str> = listing.lines().collect();
+
+        assert_eq!(lines[2], "                                                        2  INCLUDE \"io.k91\"");
+        assert_eq!(lines[3], "                                                           ==> io.k91 <==");
+        assert_eq!(lines[4], "    1  04200000     69206016  OUT   R1, =0              1  out r1, =CRT");
+        assert_eq!(lines[5], "                                                           ==> main.k91 <==");
+        assert_eq!(lines[6], "    2  70C0000B   1891631115  SVC   SP, =11             3  svc sp, =HALT");
+    }
+
+    #[test]
+    fn test_create_listing_macro() {
+        let source = "MACRO QUIT
+    svc sp, =HALT
+ENDM
+    QUIT";
+        let listing = compile_full(source.to_string(), &CompileOptions::default()).unwrap().listing;
+        let lines: Vec<&str> = listing.lines().collect();
+
+        assert_eq!(lines[1], "                                                        1  MACRO QUIT");
+        assert_eq!(lines[2], "                                                        2      svc sp, =HALT");
+        assert_eq!(lines[3], "                                                        3  ENDM");
+        assert_eq!(lines[4], "                                                        4      QUIT");
+        assert_eq!(lines[5], "    0  70C0000B   1891631115  SVC   SP, =11            2+      svc sp, =HALT");
+    }
+}
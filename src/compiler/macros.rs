@@ -0,0 +1,420 @@
+// SPDX-FileCopyrightText: 2024 sevonj
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! TTKTK - TTK-91 ToolKit
+//!
+//! TiToMachine k91 assembler - Macro module.
+//!
+//! Macros are defined with "MACRO name param1, param2", followed by the body and "ENDM". A call
+//! looks like an instruction: "[label] name arg1, arg2". Each call is replaced by the body, with
+//! parameters replaced by arguments. Labels defined in the body get a unique suffix on each call,
+//! so a macro can be called more than once. A macro has to be defined before it's called.
+//!
+use std::collections::HashMap;
+use std::ops::Range;
+use crate::compiler::{Diagnostic, ErrorCode, Keyword, str_to_keyword_type};
+use crate::compiler::preprocessor::{MacroCall, SourceLine};
+use crate::compiler::tokenizer::{Token, tokenize_line};
+
+/// Macro calls nested deeper than this are assumed to be runaway recursion.
+const MAX_MACRO_DEPTH: usize = 64;
+
+struct Macro {
+    params: Vec<String>,
+    body: Vec<SourceLine>,
+    /// The MACRO line.
+    header: SourceLine,
+    name: Token,
+}
+
+/// Macros defined so far. Fed one line at a time by the preprocessor.
+#[derive(Default)]
+pub struct Macros {
+    macros: HashMap<String, Macro>,
+    /// Macro being defined, and whether it's good enough to be used.
+    current: Option<(Macro, bool)>,
+    /// Inside a definition that IF turned off.
+    skipping: bool,
+    /// How many calls have been expanded. Used to make labels unique.
+    count: usize,
+}
+
+impl Macros {
+    /// Take the line if it's part of a definition: MACRO, ENDM, or a line in between.
+    /// Returns false if the line is something else.
+    pub fn define(&mut self, source_line: &SourceLine, words: &[Token], active: bool, errors: &mut Vec<Diagnostic>) -> bool {
+        let first = words.first().map(|word| word.text.to_uppercase());
+        let second = words.get(1).map(|word| word.text.to_uppercase());
+
+        // Inside a definition
+        if self.skipping {
+            self.skipping = first.as_deref() != Some("ENDM");
+            return true;
+        }
+        if let Some((definition, _)) = self.current.as_mut() {
+            match first.as_deref() {
+                Some("ENDM") => {
+                    let (definition, valid) = self.current.take().unwrap();
+                    let key = definition.name.text.to_uppercase();
+                    if let Some(existing) = self.macros.get(&key) {
+                        errors.push(definition.header.error_at(&definition.name, ErrorCode::MacroDefinition, format!("Macro '{}' is defined more than once", definition.name.text))
+                            .with_help(format!("First defined on line {}", existing.header.line)));
+                    } else if valid {
+                        self.macros.insert(key, definition);
+                    }
+                }
+                Some("MACRO") => errors.push(source_line.error_at(&words[0], ErrorCode::MacroDefinition, "Macros can't be defined inside macros")
+                    .with_help(format!("Add 'ENDM' before this to end macro '{}'", definition.name.text))),
+                _ => definition.body.push(source_line.clone()),
+            }
+            return true;
+        }
+
+        match first.as_deref() {
+            // Definitions turned off by IF are skipped whole, so that their IF blocks are left alone.
+            Some("MACRO") if !active => self.skipping = true,
+            Some("MACRO") => self.current = Some(parse_macro_header(source_line, words, errors)),
+            Some("ENDM") if active => errors.push(source_line.error_at(&words[0], ErrorCode::MacroDefinition, "Found 'ENDM' outside of a macro")),
+            Some(_) if matches!(second.as_deref(), Some("MACRO") | Some("ENDM")) && str_to_keyword_type(&words[0].text) == Keyword::None => {
+                if active {
+                    errors.push(source_line.error_at(&words[0], ErrorCode::LabeledDirective, format!("You can't label a compiler directive! '{}'", second.unwrap())));
+                }
+            }
+            Some("ENDM") => (),
+            _ => return false,
+        }
+        true
+    }
+
+    /// End of input.
+    pub fn finish(self, errors: &mut Vec<Diagnostic>) {
+        // Guard: Unfinished definition
+        if let Some((definition, _)) = self.current {
+            errors.push(definition.header.error_at(&definition.name, ErrorCode::MacroDefinition, format!("Macro '{}' has no 'ENDM'", definition.name.text)));
+        }
+    }
+
+    /// If the line is a macro call, find where the macro name is: 0, or 1 if it has a label.
+    pub fn find_call(&self, words: &[Token]) -> Option<usize> {
+        let is_macro = |word: &Token| self.macros.contains_key(&word.text.to_uppercase());
+        if is_macro(words.first()?) {
+            return Some(0);
+        }
+        if str_to_keyword_type(&words[0].text) == Keyword::None && is_macro(words.get(1)?) {
+            return Some(1);
+        }
+        None
+    }
+
+    /// Get the body of a macro call, with parameters replaced by arguments.
+    /// `call` starts from the macro name, and `stack` has the macros being expanded right now.
+    pub fn expand(&mut self, source_line: &SourceLine, call: &[Token], stack: &[String], errors: &mut Vec<Diagnostic>) -> Option<Vec<SourceLine>> {
+        let name = &call[0];
+        let key = name.text.to_uppercase();
+        let args = &call[1..];
+        let definition = &self.macros[&key];
+
+        // Guard: Argument count
+        if args.len() != definition.params.len() {
+            errors.push(source_line.error_at(name, ErrorCode::MacroArguments, format!("Macro '{}' takes {} arguments, but got {}", name.text, definition.params.len(), args.len()))
+                .with_help(format!("Defined on line {}: {}", definition.header.line, definition.header.text.trim())));
+            return None;
+        }
+
+        // Guard: Recursion
+        if let Some(start) = stack.iter().position(|called| *called == key) {
+            let cycle = stack[start..].join(" -> ");
+            errors.push(source_line.error_at(name, ErrorCode::MacroRecursion, format!("Macro calls itself: {} -> {}", cycle, key)));
+            return None;
+        }
+        if stack.len() >= MAX_MACRO_DEPTH {
+            errors.push(source_line.error_at(name, ErrorCode::MacroRecursion, format!("Macro calls are nested more than {} deep.", MAX_MACRO_DEPTH)));
+            return None;
+        }
+
+        // Parameters become arguments, and labels become unique.
+        self.count += 1;
+        let mut substitutions: HashMap<String, String> = definition.params.iter().cloned()
+            .zip(args.iter().map(|arg| arg.text.clone()))
+            .collect();
+        for body_line in &definition.body {
+            let body_words = tokenize_line(&body_line.text).words;
+            let Some(first) = body_words.first() else {
+                continue;
+            };
+            let is_label = str_to_keyword_type(&first.text) == Keyword::None
+                && !definition.params.contains(&first.text)
+                && !self.macros.contains_key(&first.text.to_uppercase());
+            if is_label {
+                substitutions.insert(first.text.clone(), format!("{}#{}", first.text, self.count));
+            }
+        }
+
+        // Body lines keep their own location, and remember where they were called from.
+        let mut macro_calls = vec![MacroCall {
+            name: name.text.clone(),
+            file: source_line.file.clone(),
+            line: source_line.line,
+        }];
+        macro_calls.extend(source_line.macro_calls.iter().cloned());
+        Some(definition.body.iter()
+            .map(|body_line| SourceLine {
+                file: body_line.file.clone(),
+                line: body_line.line,
+                text: substitute_words(&body_line.text, &substitutions),
+                skip: false,
+                macro_calls: macro_calls.clone(),
+            })
+            .collect())
+    }
+}
+
+/// Put the labels of macro calls in front of the first statement of the macro. The outermost
+/// call's label is used. If the statement already has a label, or there are more calls, the
+/// other labels become EQUs of it. Returns the EQU lines, which go after the statement.
+pub fn attach_labels(source_line: &mut SourceLine, words: &[Token], labels: &[Token]) -> Vec<SourceLine> {
+    let label = &labels[0];
+    let mut aliases: Vec<&Token> = labels[1..].iter().collect();
+    match words.first() {
+        Some(first) if str_to_keyword_type(&first.text) == Keyword::None => {
+            source_line.text = format!("{}{}", label.text, &source_line.text[first.columns.end..]);
+            aliases.push(first);
+        }
+        _ => source_line.text = format!("{} {}", label.text, source_line.text),
+    }
+    aliases.iter()
+        .map(|alias| SourceLine {
+            text: format!("{} EQU {}", alias.text, label.text),
+            ..source_line.clone()
+        })
+        .collect()
+}
+
+/// Read "MACRO name param1, param2". Also returns false if the macro can't be used.
+fn parse_macro_header(source_line: &SourceLine, words: &[Token], errors: &mut Vec<Diagnostic>) -> (Macro, bool) {
+    let mut valid = true;
+    let name = match words.get(1) {
+        Some(name) => name.clone(),
+        None => {
+            errors.push(source_line.error_at(&words[0], ErrorCode::MissingValue, "No name given for 'MACRO'"));
+            valid = false;
+            words[0].clone()
+        }
+    };
+
+    // Guard: Name is taken
+    if valid && str_to_keyword_type(&name.text) != Keyword::None {
+        errors.push(source_line.error_at(&name, ErrorCode::MacroDefinition, format!("'{}' is a keyword, so it can't be a macro name", name.text)));
+        valid = false;
+    }
+
+    let mut params: Vec<String> = Vec::new();
+    for param in words.iter().skip(2) {
+        let is_name = param.text.chars().all(is_word_char) && !param.text.starts_with(|c: char| c.is_ascii_digit());
+        if !is_name {
+            errors.push(source_line.error_at(param, ErrorCode::MacroDefinition, format!("Invalid parameter name '{}'", param.text)));
+            valid = false;
+        } else if params.contains(&param.text) {
+            errors.push(source_line.error_at(param, ErrorCode::MacroDefinition, format!("Parameter '{}' is listed twice", param.text)));
+            valid = false;
+        }
+        params.push(param.text.clone());
+    }
+
+    let definition = Macro {
+        params,
+        body: Vec::new(),
+        header: source_line.clone(),
+        name,
+    };
+    (definition, valid)
+}
+
+/// Characters that symbol and parameter names are made of.
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '.'
+}
+
+/// Replace whole words in the code part of a line. Strings and comments are left alone.
+/// A replacement that isn't a single word is put in parentheses when it's part of a bigger
+/// expression, so that "=n*2" with "1+1" becomes "=(1+1)*2".
+fn substitute_words(text: &str, substitutions: &HashMap<String, String>) -> String {
+    let operands: Vec<Range<usize>> = tokenize_line(text).words.into_iter().map(|word| word.columns).collect();
+    let mut result = String::new();
+    let mut word_start: Option<usize> = None;
+    let mut in_string = false;
+    let mut escaped = false;
+
+    let flush = |result: &mut String, range: Range<usize>| {
+        let word = &text[range.clone()];
+        match substitutions.get(word) {
+            Some(replacement) if !replacement.chars().all(is_word_char) && !operands.contains(&range) => {
+                *result += &format!("({})", replacement);
+            }
+            Some(replacement) => *result += replacement,
+            None => *result += word,
+        }
+    };
+
+    for (i, c) in text.char_indices() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => (),
+            }
+            result.push(c);
+            continue;
+        }
+        if is_word_char(c) {
+            word_start.get_or_insert(i);
+            continue;
+        }
+        if let Some(start) = word_start.take() {
+            flush(&mut result, start..i);
+        }
+        if c == ';' {
+            result += &text[i..];
+            return result;
+        }
+        in_string = c == '"';
+        result.push(c);
+    }
+    if let Some(start) = word_start {
+        flush(&mut result, start..text.len());
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::CompileOptions;
+    use crate::compiler::preprocessor::preprocess;
+
+    fn expand(source: &str) -> Result<Vec<SourceLine>, Vec<Diagnostic>> {
+        preprocess(source, &CompileOptions::default())
+    }
+
+    /// Lines that would be assembled.
+    fn code(lines: &[SourceLine]) -> Vec<&str> {
+        lines.iter().filter(|line| !line.skip).map(|line| line.text.as_str()).collect()
+    }
+
+    #[test]
+    fn test_substitute_words() {
+        let substitutions = HashMap::from([
+            ("x".to_string(), "r1".to_string()),
+            ("loop".to_string(), "loop#1".to_string()),
+        ]);
+        assert_eq!(substitute_words("loop add x, =x+1(x) ; x", &substitutions), "loop#1 add r1, =r1+1(r1) ; x");
+        assert_eq!(substitute_words("jump loop", &substitutions), "jump loop#1");
+        assert_eq!(substitute_words("dc xx, \"x\"", &substitutions), "dc xx, \"x\"");
+
+        // Expressions are kept together, but whole operands are left as they are.
+        let substitutions = HashMap::from([
+            ("n".to_string(), "1+1".to_string()),
+            ("m".to_string(), "=5".to_string()),
+        ]);
+        assert_eq!(substitute_words("load r1, =n*2", &substitutions), "load r1, =(1+1)*2");
+        assert_eq!(substitute_words("IF n > 1", &substitutions), "IF (1+1) > 1");
+        assert_eq!(substitute_words("dc n, -n", &substitutions), "dc 1+1, -(1+1)");
+        assert_eq!(substitute_words("load r1, m", &substitutions), "load r1, =5");
+    }
+
+    #[test]
+    fn test_expand_macros() {
+        let source = "
+MACRO SWAP a, b
+    push sp, a
+    load a, b
+    pop sp, b
+ENDM
+start SWAP r1, r2
+    swap r3, r4";
+        let lines = expand(source).unwrap();
+        assert_eq!(code(&lines), vec![
+            "",
+            "start     push sp, r1",
+            "    load r1, r2",
+            "    pop sp, r2",
+            "    push sp, r3",
+            "    load r3, r4",
+            "    pop sp, r4",
+        ]);
+
+        // Body lines point at the macro, and remember the call.
+        let pop = lines.iter().rev().find(|line| !line.skip).unwrap();
+        assert_eq!(pop.line, 5);
+        assert_eq!(pop.macro_calls, vec![MacroCall { name: "swap".into(), file: "".into(), line: 8 }]);
+    }
+
+    #[test]
+    fn test_expand_macros_unique_labels() {
+        let source = "
+MACRO WAIT n
+        load r1, =n
+loop    sub r1, =1
+        jnzer r1, loop
+ENDM
+        WAIT 10
+        WAIT 20";
+        let lines = expand(source).unwrap();
+        assert_eq!(code(&lines), vec![
+            "",
+            "        load r1, =10",
+            "loop#1    sub r1, =1",
+            "        jnzer r1, loop#1",
+            "        load r1, =20",
+            "loop#2    sub r1, =1",
+            "        jnzer r1, loop#2",
+        ]);
+
+        // Label on a call to a macro that starts with a label
+        let lines = expand("MACRO SPIN\nloop    jump loop\nENDM\nstart   SPIN").unwrap();
+        assert_eq!(code(&lines), vec![
+            "start    jump loop#1",
+            "loop#1 EQU start",
+        ]);
+    }
+
+    #[test]
+    fn test_expand_macros_nested() {
+        let source = "
+MACRO OUTER x
+    INNER x+1
+ENDM
+MACRO INNER y
+    load r1, =y
+ENDM
+    OUTER 5";
+        let lines = expand(source).unwrap();
+        let line = lines.iter().find(|line| !line.skip && !line.text.is_empty()).unwrap();
+        assert_eq!(line.text, "    load r1, =(5+1)");
+        assert_eq!(line.macro_calls.len(), 2);
+        assert_eq!(line.macro_calls[0].name, "INNER");
+        assert_eq!(line.macro_calls[1].name, "OUTER");
+    }
+
+    #[test]
+    fn test_expand_macros_errors() {
+        let errors = expand("MACRO A\n B\nENDM\nMACRO B\n A\nENDM\n A").unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].code, ErrorCode::MacroRecursion);
+        assert_eq!(errors[0].message, "Macro calls itself: A -> B -> A");
+        assert_eq!(errors[0].line, 5);
+        assert_eq!(errors[0].notes, vec!["In macro 'B', called from line 2", "In macro 'A', called from line 7"]);
+
+        let source = "MACRO M a\nnop\nENDM\nM\nENDM\nMACRO load\nENDM\nx MACRO Y\nMACRO Z";
+        let codes: Vec<ErrorCode> = expand(source).unwrap_err().iter().map(|e| e.code).collect();
+        assert_eq!(codes, vec![
+            ErrorCode::MacroArguments,
+            ErrorCode::MacroDefinition, // ENDM outside
+            ErrorCode::MacroDefinition, // load is a keyword
+            ErrorCode::LabeledDirective,
+            ErrorCode::MacroDefinition, // no ENDM
+        ]);
+    }
+}
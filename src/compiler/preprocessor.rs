@@ -0,0 +1,335 @@
+// SPDX-FileCopyrightText: 2024 sevonj
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! TTKTK - TTK-91 ToolKit
+//!
+//! TiToMachine k91 assembler - Preprocessor module. Applies conditionals, pulls in included files
+//! and expands macros.
+//!
+use crate::compiler::{CompileOptions, Diagnostic, ErrorCode, Keyword, str_to_keyword_type};
+use crate::compiler::conditional::{Conditionals, is_conditional};
+use crate::compiler::macros::{attach_labels, Macros};
+use crate::compiler::tokenizer::{parse_string_literal, Token, tokenize_line};
+
+/// Includes deeper than this are assumed to be runaway recursion.
+const MAX_INCLUDE_DEPTH: usize = 64;
+
+/// A line of source code, and where it came from.
+#[derive(Clone, PartialEq, Debug)]
+pub struct SourceLine {
+    /// Name of the file. Empty for the main source, if it wasn't given a name.
+    pub file: String,
+    /// Line number within the file, starting from 1.
+    pub line: usize,
+    pub text: String,
+    /// Line was consumed by the preprocessor. It's kept for the listing, but not assembled.
+    pub skip: bool,
+    /// If this line came from a macro, the calls that led here. Innermost first.
+    pub macro_calls: Vec<MacroCall>,
+}
+
+/// Where a macro was called from.
+#[derive(Clone, PartialEq, Debug)]
+pub struct MacroCall {
+    pub name: String,
+    pub file: String,
+    pub line: usize,
+}
+
+impl MacroCall {
+    /// Describe the call, for diagnostics.
+    pub fn note(&self) -> String {
+        match self.file.is_empty() {
+            true => format!("In macro '{}', called from line {}", self.name, self.line),
+            false => format!("In macro '{}', called from {}, line {}", self.name, self.file, self.line),
+        }
+    }
+}
+
+impl SourceLine {
+    /// Create an error that points at a token on this line.
+    pub fn error_at(&self, token: &Token, code: ErrorCode, message: impl Into<String>) -> Diagnostic {
+        let mut error = Diagnostic::error(code, self.line, message)
+            .with_file(self.file.as_str())
+            .with_columns(token.columns.clone())
+            .with_token(token.text.as_str());
+        for call in &self.macro_calls {
+            error = error.with_note(call.note());
+        }
+        error
+    }
+}
+
+/// Split source code into lines.
+pub fn split_lines(source: &str, file: &str) -> Vec<SourceLine> {
+    source.lines().enumerate()
+        .map(|(i, text)| SourceLine {
+            file: file.to_string(),
+            line: i + 1,
+            text: text.to_string(),
+            skip: false,
+            macro_calls: Vec::new(),
+        })
+        .collect()
+}
+
+/// Turn the main source into the full list of lines to be assembled: each INCLUDE line is
+/// followed by the lines of the included file, and each macro call by the lines of the macro.
+/// This is done in one pass from top to bottom, which also applies IF blocks. Lines turned off by
+/// them are skipped, so their includes and macros are left alone.
+pub fn preprocess(source: &str, options: &CompileOptions) -> Result<Vec<SourceLine>, Vec<Diagnostic>> {
+    let mut preprocessor = Preprocessor {
+        options,
+        conditionals: Conditionals::new(options),
+        macros: Macros::default(),
+        include_stack: vec![options.source_name.clone()],
+        macro_stack: Vec::new(),
+        labels: Vec::new(),
+        output: Vec::new(),
+        errors: Vec::new(),
+    };
+    preprocessor.process(split_lines(source, &options.source_name));
+
+    let mut errors = preprocessor.errors;
+    preprocessor.macros.finish(&mut errors);
+    if let Err(mut e) = preprocessor.conditionals.finish() {
+        errors.append(&mut e);
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    Ok(preprocessor.output)
+}
+
+struct Preprocessor<'a> {
+    options: &'a CompileOptions,
+    conditionals: Conditionals,
+    macros: Macros,
+    /// Files being included right now, outermost first.
+    include_stack: Vec<String>,
+    /// Macros being expanded right now, outermost first.
+    macro_stack: Vec<String>,
+    /// Labels of macro calls, waiting for the first statement of the macro. Outermost first.
+    labels: Vec<Token>,
+    output: Vec<SourceLine>,
+    errors: Vec<Diagnostic>,
+}
+
+impl Preprocessor<'_> {
+    /// Copy lines to output, replacing includes with file contents and macro calls with macro bodies.
+    fn process(&mut self, lines: Vec<SourceLine>) {
+        for mut source_line in lines {
+            let mut words = tokenize_line(&source_line.text).words;
+            if self.macros.define(&source_line, &words, self.conditionals.active(), &mut self.errors) {
+                source_line.skip = true;
+                self.output.push(source_line);
+                continue;
+            }
+
+            // Label of a macro call goes to the first statement of the macro.
+            let mut aliases = Vec::new();
+            let is_statement = !words.is_empty()
+                && find_keyword(&words, is_conditional).is_none()
+                && self.macros.find_call(&words).is_none();
+            if !self.labels.is_empty() && is_statement && self.conditionals.active() {
+                aliases = attach_labels(&mut source_line, &words, &self.labels);
+                self.labels.clear();
+                words = tokenize_line(&source_line.text).words;
+            }
+
+            if !self.conditionals.apply(&mut source_line) {
+                self.output.push(source_line);
+                continue;
+            }
+            if let Some(index) = find_keyword(&words, |keyword| keyword == "INCLUDE") {
+                self.include(source_line, &words, index);
+                continue;
+            }
+            if let Some(index) = self.macros.find_call(&words) {
+                self.call(source_line, &words, index);
+                continue;
+            }
+            self.output.push(source_line);
+            for mut alias in aliases {
+                self.conditionals.apply(&mut alias);
+                self.output.push(alias);
+            }
+        }
+    }
+
+    /// Replace a macro call with the macro body. `index` is where the macro name is.
+    fn call(&mut self, mut source_line: SourceLine, words: &[Token], index: usize) {
+        source_line.skip = true;
+        let body = self.macros.expand(&source_line, &words[index..], &self.macro_stack, &mut self.errors);
+        // Call line stays, so that it shows up in the listing.
+        self.output.push(source_line.clone());
+        let Some(body) = body else {
+            return;
+        };
+
+        let labels = self.labels.len();
+        if index > 0 {
+            self.labels.push(words[0].clone());
+        }
+        self.macro_stack.push(words[index].text.to_uppercase());
+        self.process(body);
+        self.macro_stack.pop();
+
+        // Guard: Label wasn't used
+        if self.labels.len() > labels {
+            self.labels.truncate(labels);
+            self.errors.push(source_line.error_at(&words[0], ErrorCode::MissingKeyword, format!("Can't label this call: macro '{}' has no statements", words[index].text)));
+        }
+    }
+
+    /// Replace an include with file contents. `index` is where the keyword is.
+    fn include(&mut self, source_line: SourceLine, words: &[Token], index: usize) {
+        // Guard: Label
+        if index > 0 {
+            self.errors.push(source_line.error_at(&words[0], ErrorCode::LabeledDirective, "You can't label a compiler directive! 'INCLUDE'"));
+            return;
+        }
+
+        // Guard: Incorrect number of words
+        match words.len() {
+            2 => (), // expected amount
+            1 => {
+                self.errors.push(source_line.error_at(&words[0], ErrorCode::MissingValue, "No file given for 'INCLUDE'"));
+                return;
+            }
+            _ => {
+                self.errors.push(source_line.error_at(&words[2], ErrorCode::TooManyValues, "Too many words for 'INCLUDE'"));
+                return;
+            }
+        }
+
+        // Get file name
+        let path = match parse_string_literal(&words[1].text) {
+            Ok(path) => path,
+            Err(e) => {
+                self.errors.push(source_line.error_at(&words[1], ErrorCode::InvalidString, e));
+                return;
+            }
+        };
+
+        // Get file
+        let Some(resolver) = &self.options.file_resolver else {
+            self.errors.push(source_line.error_at(&words[1], ErrorCode::IncludeNotFound, format!("Can't include '{}': No file resolver was given.", path))
+                .with_help("Set CompileOptions::file_resolver to enable INCLUDE."));
+            return;
+        };
+        let (name, contents) = match resolver.resolve(&path, &source_line.file) {
+            Ok(file) => file,
+            Err(e) => {
+                self.errors.push(source_line.error_at(&words[1], ErrorCode::IncludeNotFound, e));
+                return;
+            }
+        };
+
+        // Guard: Cycle
+        if let Some(start) = self.include_stack.iter().position(|file| *file == name) {
+            let cycle = self.include_stack[start..].join(" -> ");
+            self.errors.push(source_line.error_at(&words[1], ErrorCode::IncludeCycle, format!("Include cycle: {} -> {}", cycle, name)));
+            return;
+        }
+        if self.include_stack.len() > MAX_INCLUDE_DEPTH {
+            self.errors.push(source_line.error_at(&words[1], ErrorCode::IncludeCycle, format!("Includes are nested more than {} deep.", MAX_INCLUDE_DEPTH)));
+            return;
+        }
+
+        // Include line stays, so that it shows up in the listing.
+        self.output.push(source_line);
+        self.include_stack.push(name.clone());
+        self.process(split_lines(&contents, &name));
+        self.include_stack.pop();
+    }
+}
+
+/// If the line is a directive that `is_keyword` accepts, find where the keyword is: 0, or 1 if
+/// it has a label.
+fn find_keyword(words: &[Token], is_keyword: impl Fn(&str) -> bool) -> Option<usize> {
+    if is_keyword(&words.first()?.text.to_uppercase()) {
+        return Some(0);
+    }
+    if str_to_keyword_type(&words[0].text) == Keyword::None && is_keyword(&words.get(1)?.text.to_uppercase()) {
+        return Some(1);
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use super::*;
+
+    fn options_with_files(files: &[(&str, &str)]) -> CompileOptions {
+        let files: HashMap<String, String> = files.iter()
+            .map(|(name, contents)| (name.to_string(), contents.to_string()))
+            .collect();
+        CompileOptions {
+            source_name: "main.k91".into(),
+            file_resolver: Some(Arc::new(files)),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_preprocess_include() {
+        let options = options_with_files(&[
+            ("io.k91", "print nop\nINCLUDE \"util.k91\""),
+            ("util.k91", "util nop"),
+        ]);
+        let lines = preprocess("start nop\nINCLUDE \"io.k91\" ; helpers\nsvc sp, =HALT", &options).unwrap();
+        let lines: Vec<(&str, usize, &str)> = lines.iter().map(|l| (l.file.as_str(), l.line, l.text.as_str())).collect();
+        assert_eq!(lines, vec![
+            ("main.k91", 1, "start nop"),
+            ("main.k91", 2, "INCLUDE \"io.k91\" ; helpers"),
+            ("io.k91", 1, "print nop"),
+            ("io.k91", 2, "INCLUDE \"util.k91\""),
+            ("util.k91", 1, "util nop"),
+            ("main.k91", 3, "svc sp, =HALT"),
+        ]);
+    }
+
+    #[test]
+    fn test_preprocess_include_cycle() {
+        let options = options_with_files(&[
+            ("a.k91", "INCLUDE \"b.k91\""),
+            ("b.k91", "nop\nINCLUDE \"a.k91\""),
+        ]);
+        let errors = preprocess("INCLUDE \"a.k91\"", &options).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].code, ErrorCode::IncludeCycle);
+        assert_eq!(errors[0].file, "b.k91");
+        assert_eq!(errors[0].line, 2);
+        assert_eq!(errors[0].message, "Include cycle: a.k91 -> b.k91 -> a.k91");
+    }
+
+    #[test]
+    fn test_preprocess_include_errors() {
+        let options = options_with_files(&[]);
+        let source = "INCLUDE \"nope.k91\"\nlabel INCLUDE \"x\"\nINCLUDE nope.k91\nINCLUDE\njump include";
+        let codes: Vec<ErrorCode> = preprocess(source, &options).unwrap_err().iter().map(|e| e.code).collect();
+        assert_eq!(codes, vec![ErrorCode::IncludeNotFound, ErrorCode::LabeledDirective, ErrorCode::InvalidString, ErrorCode::MissingValue]);
+
+        // No resolver
+        let errors = preprocess("INCLUDE \"io.k91\"", &CompileOptions::default()).unwrap_err();
+        assert_eq!(errors[0].code, ErrorCode::IncludeNotFound);
+    }
+
+    #[test]
+    fn test_preprocess_conditionals() {
+        let options = options_with_files(&[("debug.k91", "x nop")]);
+        let source = "IF 0\nINCLUDE \"missing.k91\"\nELSE\nINCLUDE \"debug.k91\"\nENDIF\nIFDEF x\ny nop\nENDIF";
+        let lines = preprocess(source, &options).unwrap();
+        let kept: Vec<&str> = lines.iter().filter(|l| !l.skip).map(|l| l.text.as_str()).collect();
+        assert_eq!(kept, vec!["INCLUDE \"debug.k91\"", "x nop", "y nop"]);
+        assert_eq!(lines.len(), 9);
+
+        // Unfinished block
+        let errors = preprocess("IF 1\nnop", &options).unwrap_err();
+        assert_eq!(errors[0].code, ErrorCode::Conditional);
+    }
+}
@@ -0,0 +1,235 @@
+// SPDX-FileCopyrightText: 2024 sevonj
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! TTKTK - TTK-91 ToolKit
+//!
+//! TiToMachine k91 assembler - Symbol resolver module.
+//!
+//! Constants may refer to other constants and labels defined anywhere in the file, and labels in
+//! the data segment depend on the sizes of earlier DS statements, which may refer to constants.
+//! Everything is resolved on demand, so each value is computed only after the values it depends on.
+//!
+use std::collections::{HashMap, HashSet};
+use crate::compiler::{Diagnostic, ErrorCode, Keyword, parse_const, parse_string_data, Statement, str_to_builtin_const};
+use crate::compiler::expression::expression_symbols;
+
+/// Something that gets resolved: a symbol, or the size of a DS statement.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+enum Key {
+    Symbol(String),
+    /// Statement index. Line numbers aren't unique across includes and macro calls.
+    Size(usize),
+}
+
+pub struct SymbolResolver<'a> {
+    statements: &'a [Statement],
+    code_start: usize,
+    data_start: usize,
+    /// Symbol name -> index of the statement that defines it.
+    definitions: HashMap<&'a str, usize>,
+    /// Statement index -> offset within code segment.
+    code_offsets: HashMap<usize, usize>,
+    /// Indices of data statements, in the order they are laid out.
+    data_statements: Vec<usize>,
+    /// Resolved symbols, starting with defines. Labels have absolute addresses.
+    values: HashMap<String, i32>,
+    /// Resolved DS sizes by statement index.
+    sizes: HashMap<usize, i32>,
+    /// Symbols and sizes that couldn't be resolved. Their errors have been reported already.
+    failed: HashSet<Key>,
+    /// What is being resolved right now, outermost first. Used to find cycles.
+    stack: Vec<Key>,
+    pub errors: Vec<Diagnostic>,
+}
+
+impl<'a> SymbolResolver<'a> {
+    pub fn new(statements: &'a [Statement], defines: &HashMap<String, i32>, code_start: usize, data_start: usize) -> Self {
+        let mut definitions = HashMap::new();
+        let mut code_offsets = HashMap::new();
+        let mut data_statements = Vec::new();
+        for (index, statement) in statements.iter().enumerate() {
+            match statement.statement_type {
+                Keyword::Code => {
+                    code_offsets.insert(index, code_offsets.len());
+                }
+                Keyword::Data => data_statements.push(index),
+                _ => (),
+            }
+            if let Some(label) = &statement.label {
+                // Multiple definitions are reported elsewhere. The first one counts.
+                definitions.entry(label.text.as_str()).or_insert(index);
+            }
+        }
+        SymbolResolver {
+            statements,
+            code_start,
+            data_start,
+            definitions,
+            code_offsets,
+            data_statements,
+            values: defines.clone(),
+            sizes: HashMap::new(),
+            failed: HashSet::new(),
+            stack: Vec::new(),
+            errors: Vec::new(),
+        }
+    }
+
+    /// Value of a builtin or a symbol that has been resolved already.
+    fn lookup(&self, name: &str) -> Option<i32> {
+        str_to_builtin_const(name).ok().or_else(|| self.values.get(name).copied())
+    }
+
+    /// Get the value of a symbol, resolving whatever it depends on first.
+    /// None if the symbol is undefined, or if it's broken. Broken symbols report their own errors.
+    pub fn resolve(&mut self, name: &str) -> Option<i32> {
+        if let Some(value) = self.lookup(name) {
+            return Some(value);
+        }
+        let key = Key::Symbol(name.to_string());
+        if self.failed.contains(&key) {
+            return None;
+        }
+        let index = *self.definitions.get(name)?;
+
+        // Guard: Cycle
+        if !self.enter(&key, index) {
+            return None;
+        }
+        let value = match self.statements[index].statement_type {
+            Keyword::Code => Some((self.code_start + self.code_offsets[&index]) as i32),
+            Keyword::Data => self.data_address(index),
+            Keyword::Const => self.resolve_const(index),
+            _ => None,
+        };
+        self.leave(&key, value);
+        value
+    }
+
+    /// Size of a data statement, in addresses.
+    /// None if it's broken. Broken sizes report their own errors.
+    pub fn data_size(&mut self, index: usize) -> Option<i32> {
+        let statements = self.statements;
+        let statement = &statements[index];
+        match statement.words[0].text.to_uppercase().as_str() {
+            "DS" => (),
+            // Broken strings are reported when the data is made.
+            "STRING" | "STRINGZ" => return Some(parse_string_data(statement).map_or(1, |data| data.len() as i32)),
+            // One address per value. Missing values are reported when the data is made.
+            _ => return Some(statement.words.len().max(2) as i32 - 1),
+        }
+        if let Some(size) = self.sizes.get(&index) {
+            return Some(*size);
+        }
+        // Missing size is reported elsewhere.
+        let word = statement.words.get(1)?;
+        let key = Key::Size(index);
+        if self.failed.contains(&key) {
+            return None;
+        }
+
+        // Guard: Cycle
+        if !self.enter(&key, index) {
+            return None;
+        }
+        let size = match self.resolve_dependencies(statement, &word.text) {
+            true => match statement.eval_word(word, |name| self.lookup(name)) {
+                Ok(size) => Some(size as i32),
+                Err(e) => {
+                    self.errors.push(e);
+                    None
+                }
+            },
+            false => None,
+        };
+        self.leave(&key, size);
+        if let Some(size) = size {
+            self.sizes.insert(index, size);
+        }
+        size
+    }
+
+    /// Address of a data statement: data start, plus the sizes of everything before it.
+    fn data_address(&mut self, index: usize) -> Option<i32> {
+        let mut address = self.data_start as i32;
+        let position = self.data_statements.iter().position(|&i| i == index)?;
+        for i in 0..position {
+            address += self.data_size(self.data_statements[i])?;
+        }
+        Some(address)
+    }
+
+    fn resolve_const(&mut self, index: usize) -> Option<i32> {
+        let statements = self.statements;
+        let statement = &statements[index];
+        if let Some(word) = statement.words.get(1) {
+            if !self.resolve_dependencies(statement, &word.text) {
+                return None;
+            }
+        }
+        match parse_const(statement, |name| self.lookup(name)) {
+            Ok(value) => Some(value),
+            Err(e) => {
+                self.errors.push(e);
+                None
+            }
+        }
+    }
+
+    /// Resolve every symbol an expression refers to.
+    /// False if any of them is broken, in which case the expression shouldn't be evaluated.
+    /// Undefined symbols are fine here; evaluating the expression will report them.
+    fn resolve_dependencies(&mut self, statement: &Statement, expression: &str) -> bool {
+        for name in expression_symbols(expression) {
+            let name = statement.qualify(&name).into_owned();
+            if self.resolve(&name).is_none() && self.failed.contains(&Key::Symbol(name)) {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Mark something as being resolved. If it's being resolved already, we went around in a
+    /// circle: report it and return false.
+    fn enter(&mut self, key: &Key, index: usize) -> bool {
+        let Some(start) = self.stack.iter().position(|k| k == key) else {
+            self.stack.push(key.clone());
+            return true;
+        };
+        let cycle = self.stack[start..].to_vec();
+        let statement = &self.statements[index];
+        let mut path: Vec<String> = cycle.iter().map(|k| self.describe(k)).collect();
+        path.push(self.describe(key));
+        let path = path.join(" -> ");
+        let error = match &statement.label {
+            Some(label) => statement.error_at(label, ErrorCode::CircularDefinition, format!("Circular definition: {}", path)),
+            None => statement.error(ErrorCode::CircularDefinition, format!("Circular definition: {}", path)),
+        };
+        self.errors.push(error.with_help("These values depend on each other, so none of them can be computed."));
+        self.failed.extend(cycle);
+        false
+    }
+
+    fn leave(&mut self, key: &Key, value: Option<i32>) {
+        self.stack.pop();
+        match (key, value) {
+            (Key::Symbol(name), Some(value)) => {
+                self.values.insert(name.clone(), value);
+            }
+            // DS sizes are kept by the caller.
+            (Key::Size(_), Some(_)) => (),
+            (_, None) => {
+                self.failed.insert(key.clone());
+            }
+        }
+    }
+
+    /// Name of a key in error messages.
+    fn describe(&self, key: &Key) -> String {
+        match key {
+            Key::Symbol(name) => name.clone(),
+            Key::Size(index) => format!("DS on line {}", self.statements[*index].line),
+        }
+    }
+}
@@ -0,0 +1,272 @@
+// SPDX-FileCopyrightText: 2024 sevonj
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! TTKTK - TTK-91 ToolKit
+//!
+//! TiToMachine k91 assembler - Tokenizer module.
+//!
+use std::ops::Range;
+use crate::compiler::{Keyword, str_to_keyword_type};
+
+/// A piece of source code, and where it was found on its line.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Token {
+    pub text: String,
+    /// Byte range within the line.
+    pub columns: Range<usize>,
+    /// A comma separates this from the word before it.
+    pub after_comma: bool,
+}
+
+/// A line of source code split into words and a comment.
+#[derive(Debug)]
+pub struct TokenizedLine {
+    pub words: Vec<Token>,
+    /// Everything after the ';', if there was one.
+    pub comment: Option<Token>,
+}
+
+impl Token {
+    pub fn new(text: &str, columns: Range<usize>) -> Self {
+        Token {
+            text: text.to_string(),
+            columns,
+            after_comma: false,
+        }
+    }
+
+    /// Get a part of this token. The range is relative to the start of the token.
+    pub fn slice(&self, range: Range<usize>) -> Token {
+        let start = self.columns.start;
+        Token::new(&self.text[range.clone()], start + range.start..start + range.end)
+    }
+}
+
+/// Characters that can only be binary operators in an expression.
+const BINARY_OPERATOR_CHARS: [char; 8] = ['*', '/', '%', '<', '>', '&', '|', '^'];
+/// Characters that can be either unary or binary operators.
+const UNARY_OPERATOR_CHARS: [char; 3] = ['+', '-', '~'];
+/// Comparisons that end with '='. '=' alone is a mode sign, so it can't glue words by itself.
+const COMPARISON_OPERATORS: [&str; 4] = ["==", "!=", "<=", ">="];
+
+/// Split a line into words. Words are separated by whitespace and commas, and a ';' starts a
+/// comment that lasts until the end of the line.
+///
+/// Expressions stay in one piece: whitespace inside parentheses doesn't split, and neither does
+/// whitespace around an operator. "=(SIZE - 1) * 2" and "x +1" are one word each. A sign after a
+/// keyword or register starts a new word, so "R1 -1" is still two. So does a sign in a DC or DS
+/// list, where "1 -2" is two values with a missing comma. Commas always split.
+pub fn tokenize_line(text: &str) -> TokenizedLine {
+    let (code, comment) = match find_comment_start(text) {
+        Some(pos) => (&text[..pos], Some(Token::new(&text[pos + 1..], pos + 1..text.len()))),
+        None => (text, None),
+    };
+
+    // Word ranges, and whether there was a comma before each.
+    let mut ranges: Vec<(Range<usize>, bool)> = Vec::new();
+    let mut word_start: Option<usize> = None;
+    let mut comma = false;
+    let mut depth = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in code.char_indices() {
+        // Strings are never split.
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => (),
+            }
+            continue;
+        }
+        match c {
+            '(' => depth += 1,
+            ')' => depth = (depth - 1).max(0),
+            '"' => in_string = true,
+            _ => (),
+        }
+        if depth == 0 && (c.is_whitespace() || c == ',') {
+            if let Some(start) = word_start.take() {
+                ranges.push((start..i, comma));
+                comma = false;
+            }
+            comma |= c == ',';
+        } else if word_start.is_none() {
+            word_start = Some(i);
+        }
+    }
+    if let Some(start) = word_start {
+        ranges.push((start..code.len(), comma));
+    }
+
+    // Glue together words that are connected by an operator.
+    let mut words: Vec<Token> = Vec::new();
+    for (range, comma) in ranges {
+        let word = &code[range.clone()];
+        let is_data_list = words.iter().take(2).any(|word| matches!(word.text.to_uppercase().as_str(), "DC" | "DS"));
+        if let Some(previous) = words.last_mut() {
+            let joins = !comma && (
+                previous.text.ends_with(BINARY_OPERATOR_CHARS)
+                    || previous.text.ends_with(UNARY_OPERATOR_CHARS)
+                    || word.starts_with(BINARY_OPERATOR_CHARS)
+                    || (word.starts_with(['+', '-']) && !is_data_list && str_to_keyword_type(&previous.text) == Keyword::None)
+                    || COMPARISON_OPERATORS.iter().any(|operator| previous.text.ends_with(operator) || word.starts_with(operator))
+                    || word.chars().all(|c| BINARY_OPERATOR_CHARS.contains(&c) || UNARY_OPERATOR_CHARS.contains(&c))
+            );
+            if joins {
+                let columns = previous.columns.start..range.end;
+                previous.text = code[columns.clone()].to_string();
+                previous.columns = columns;
+                continue;
+            }
+        }
+        let mut token = Token::new(word, range);
+        token.after_comma = comma;
+        words.push(token);
+    }
+
+    TokenizedLine {
+        words,
+        comment,
+    }
+}
+
+/// Find the ';' that starts a comment. Semicolons inside strings don't count.
+fn find_comment_start(text: &str) -> Option<usize> {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in text.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            ';' if !in_string => return Some(i),
+            _ => (),
+        }
+    }
+    None
+}
+
+/// Read a string literal: text in double quotes. Supports escapes `\n`, `\t`, `\r`, `\0`, `\\`,
+/// `\"`, and `\'`.
+pub fn parse_string_literal(text: &str) -> Result<String, String> {
+    let Some(inner) = text.strip_prefix('"').and_then(|rest| rest.strip_suffix('"')) else {
+        return Err(format!("Expected a string in double quotes, got '{}'", text));
+    };
+    let mut string = String::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            string.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => string.push('\n'),
+            Some('t') => string.push('\t'),
+            Some('r') => string.push('\r'),
+            Some('0') => string.push('\0'),
+            Some('\\') => string.push('\\'),
+            Some('"') => string.push('"'),
+            Some('\'') => string.push('\''),
+            Some(other) => return Err(format!("Unknown escape sequence '\\{}'", other)),
+            None => return Err("String ends with a lone '\\'".to_string()),
+        }
+    }
+    Ok(string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokenize_line_columns() {
+        let line = tokenize_line("loop  LOAD R1,=5(R2) ; comment");
+        let words: Vec<(&str, Range<usize>)> = line.words.iter().map(|t| (t.text.as_str(), t.columns.clone())).collect();
+        assert_eq!(words, vec![
+            ("loop", 0..4),
+            ("LOAD", 6..10),
+            ("R1", 11..13),
+            ("=5(R2)", 14..20),
+        ]);
+        assert_eq!(line.comment, Some(Token::new(" comment", 22..30)));
+    }
+
+    #[test]
+    fn test_tokenize_line_empty() {
+        let line = tokenize_line("   \t ");
+        assert!(line.words.is_empty());
+        assert!(line.comment.is_none());
+
+        let line = tokenize_line(";");
+        assert!(line.words.is_empty());
+        assert_eq!(line.comment, Some(Token::new("", 1..1)));
+    }
+
+    #[test]
+    fn test_tokenize_line_commas() {
+        let line = tokenize_line("add r1,,r2");
+        let words: Vec<&str> = line.words.iter().map(|t| t.text.as_str()).collect();
+        assert_eq!(words, vec!["add", "r1", "r2"]);
+        assert_eq!(line.words[2].columns, 8..10);
+    }
+
+    #[test]
+    fn test_tokenize_line_expressions() {
+        let line = tokenize_line("load r1, =(SIZE - 1) * 2(r2)");
+        let words: Vec<&str> = line.words.iter().map(|t| t.text.as_str()).collect();
+        assert_eq!(words, vec!["load", "r1", "=(SIZE - 1) * 2(r2)"]);
+        assert_eq!(line.words[2].columns, 9..28);
+
+        let line = tokenize_line("x dc table + 4");
+        let words: Vec<&str> = line.words.iter().map(|t| t.text.as_str()).collect();
+        assert_eq!(words, vec!["x", "dc", "table + 4"]);
+
+        // Sign after a register or keyword doesn't glue, and commas always split.
+        let line = tokenize_line("add r1 -1");
+        assert_eq!(line.words.len(), 3);
+        let line = tokenize_line("load r1, x +1");
+        let words: Vec<&str> = line.words.iter().map(|t| t.text.as_str()).collect();
+        assert_eq!(words, vec!["load", "r1", "x +1"]);
+        assert!(line.words[2].after_comma);
+        let line = tokenize_line("dc 1 -2");
+        let words: Vec<&str> = line.words.iter().map(|t| t.text.as_str()).collect();
+        assert_eq!(words, vec!["dc", "1", "-2"]);
+        assert!(!line.words[2].after_comma);
+        let line = tokenize_line("x ds 1 - 2");
+        let words: Vec<&str> = line.words.iter().map(|t| t.text.as_str()).collect();
+        assert_eq!(words, vec!["x", "ds", "1 - 2"]);
+        let line = tokenize_line("dc 1 +, -2");
+        let words: Vec<&str> = line.words.iter().map(|t| t.text.as_str()).collect();
+        assert_eq!(words, vec!["dc", "1 +", "-2"]);
+
+        let line = tokenize_line("IF LEVEL == 2 ; comparison");
+        let words: Vec<&str> = line.words.iter().map(|t| t.text.as_str()).collect();
+        assert_eq!(words, vec!["IF", "LEVEL == 2"]);
+        let line = tokenize_line("IF a != b");
+        assert_eq!(line.words.len(), 2);
+    }
+
+    #[test]
+    fn test_tokenize_line_strings() {
+        let line = tokenize_line(r#"INCLUDE "my file; v2.k91" ; comment"#);
+        let words: Vec<&str> = line.words.iter().map(|t| t.text.as_str()).collect();
+        assert_eq!(words, vec!["INCLUDE", r#""my file; v2.k91""#]);
+        assert_eq!(line.comment.unwrap().text, " comment");
+
+        let line = tokenize_line(r#"x "a \" b" y"#);
+        assert_eq!(line.words.len(), 3);
+        assert_eq!(line.words[1].text, r#""a \" b""#);
+    }
+
+    #[test]
+    fn test_parse_string_literal() {
+        assert_eq!(parse_string_literal(r#""hello""#).unwrap(), "hello");
+        assert_eq!(parse_string_literal(r#""a\tb\n\"\\\0""#).unwrap(), "a\tb\n\"\\\0");
+        assert!(parse_string_literal("hello").is_err());
+        assert!(parse_string_literal(r#""hello"#).is_err());
+        assert!(parse_string_literal(r#""\q""#).is_err());
+    }
+}
@@ -6,33 +6,73 @@
 //!
 //! TTK-91 Disassembly module.
 //!
-use crate::instructions::{OpCode, Register};
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use crate::b91::B91;
+use crate::compiler::is_reserved_name;
+use crate::instructions::{DecodeError, OperandShape, Register, TTK91Instruction};
+
+/// Program couldn't be disassembled into source.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ProgramDecodeError {
+    /// Code word at this address couldn't be disassembled.
+    Instruction { address: i32, error: DecodeError },
+    /// Data segment doesn't start right after the code. Source can't leave a gap between them.
+    DataStart { code_end: i32, data_start: i32 },
+}
+
+impl Display for ProgramDecodeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProgramDecodeError::Instruction { address, error } => write!(f, "Address {}: {}", address, error),
+            ProgramDecodeError::DataStart { code_end, data_start } => {
+                write!(f, "Data segment starts at {}, but code ends at {}", data_start, code_end)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ProgramDecodeError {}
 
 /// Disassemble instruction (extended)
-/// Returns "N/A" if failed.
+/// Returns "N/A" if failed. Junk in unused fields is ignored, like when the instruction is executed.
 pub fn disassemble_instruction(input_instr: i32) -> String {
+    match TTK91Instruction::decode(input_instr) {
+        Ok(instruction) => instruction_to_string(instruction, |_| None),
+        Err(_) => "N/A".into(),
+    }
+}
 
-    // Get opcode
-    let opcode;
-    match OpCode::try_from(input_instr >> 24) {
-        Ok(value) => opcode = value,
-        Err(_) => return "N/A".into()
+/// Disassemble instruction (classic)
+/// Same as the other one, but refuses to recognize extended instructions.
+pub fn disassemble_instruction_classic(input_instr: i32) -> String {
+    match TTK91Instruction::decode(input_instr) {
+        Ok(instruction) if instruction.opcode.is_classic_isa() => instruction_to_string(instruction, |_| None),
+        _ => "N/A".into(),
     }
+}
 
-    // Get registers
-    let rj = Register::try_from((input_instr >> 21) & 0x7).unwrap();
-    let ri = Register::try_from((input_instr >> 16) & 0x7).unwrap();
+/// Disassemble instruction (extended), or tell why it can't be.
+/// Unlike [disassemble_instruction], this refuses instructions with junk in the fields they don't
+/// use, because the result wouldn't assemble back into the same word.
+pub fn try_disassemble_instruction(input_instr: i32) -> Result<String, DecodeError> {
+    disassemble_with_symbols(input_instr, |_| None)
+}
 
-    // Get address. These casts catch the sign.
-    let addr = (input_instr & 0xffff) as i16 as i32;
+/// Disassemble instruction, naming memory addresses with `symbol` where it has a name for them.
+fn disassemble_with_symbols<'a>(input_instr: i32, symbol: impl Fn(i32) -> Option<&'a str>) -> Result<String, DecodeError> {
+    let instruction = TTK91Instruction::decode(input_instr)?;
+    instruction.check_reserved_bits()?;
+    Ok(instruction_to_string(instruction, symbol))
+}
+
+/// Write a decoded instruction as source.
+fn instruction_to_string<'a>(instruction: TTK91Instruction, symbol: impl Fn(i32) -> Option<&'a str>) -> String {
+    let TTK91Instruction { opcode, rj, mode, ri, addr } = instruction;
+    let addr = addr as i32;
 
-    // Get addressing mode
-    let mut mode = (input_instr >> 19) & 0x3;
-    if mode == 3 {
-        return "N/A".into();
-    }
     // Undo mode offset from opcode.
-    mode -= opcode.get_default_mode();
+    let mut mode = mode as i32 - opcode.get_default_mode();
     // Undo mode offset from direct register addressing.
     if addr == 0 && ri != Register::R0 {
         mode += 1;
@@ -40,35 +80,161 @@ pub fn disassemble_instruction(input_instr: i32) -> String {
 
     // Return string
     let oper = format!("{:width$}", opcode.to_string(), width = 5);
-    let op2 = op2_to_string(mode, ri, addr);
+    // Immediate values aren't addresses.
+    let name = match mode {
+        -1 => None,
+        _ => symbol(addr),
+    };
+    let op2 = op2_to_string(mode, ri, addr, name);
 
     match opcode.get_operand_count() {
-        0 => return format!("{oper}"),
-        1 => return if opcode.is_op2_only() {
+        0 => oper,
+        1 => if opcode.is_op2_only() {
             format!("{oper} {op2}")
         } else {
             format!("{oper} {rj}")
         },
-        2 => return format!("{oper} {rj}, {op2}"),
-        _ => panic!("This should not be possible: '{}'", input_instr)
+        2 => format!("{oper} {rj}, {op2}"),
+        _ => panic!("This should not be possible: '{}'", instruction.encode())
     }
 }
 
-/// Disassemble instruction (classic)
-/// Same as the other one, but refuses to recognize extended instructions.
-pub fn disassemble_instruction_classic(input_instr: i32) -> String {
-    let opcode;
-    match OpCode::try_from(input_instr >> 24) {
-        Ok(value) => opcode = value,
-        Err(_) => return "N/A".into()
+/// Disassemble instruction (classic), or tell why it can't be.
+/// Extended instructions are [DecodeError::ExtendedOpCode].
+pub fn try_disassemble_instruction_classic(input_instr: i32) -> Result<String, DecodeError> {
+    let instruction = TTK91Instruction::decode(input_instr)?;
+    if !instruction.opcode.is_classic_isa() {
+        return Err(DecodeError::ExtendedOpCode(instruction.opcode));
+    }
+    try_disassemble_instruction(input_instr)
+}
+
+/// Disassemble a whole program into k91 source that assembles back into the same code.
+///
+/// Symbols that point inside the program become labels, and addresses that have a label are
+/// written with it. If an address has more than one name, the rest are EQU aliases of the first.
+/// Symbols listed in [B91::constants] are never chosen over a label, and the ones that don't
+/// alias a label are written as EQU with their value. Data is written as DC, and runs of zeros
+/// as DS. Comments are kept.
+///
+/// Fails if an instruction can't be written in source, or if data doesn't start right after code.
+pub fn disassemble_program(b91: &B91) -> Result<String, ProgramDecodeError> {
+    let code = &b91.code_segment;
+    let data = &b91.data_segment;
+    let code_end = code.start + code.content.len() as i32;
+    let data_end = data.start + data.content.len() as i32;
+    let in_program = |address: i32| (code.start..code_end).contains(&address) || (data.start..data_end).contains(&address);
+
+    // Guard: Gap or overlap between segments
+    if !data.content.is_empty() && data.start != code_end {
+        return Err(ProgramDecodeError::DataStart { code_end, data_start: data.start });
+    }
+
+    // Labels by address. Real labels come first, then constants that happen to have the same value.
+    let mut labels: BTreeMap<i32, Vec<&str>> = BTreeMap::new();
+    let mut constants: Vec<(&str, i32)> = Vec::new();
+    for (name, value) in &b91.symbol_table {
+        if !is_label_name(name) {
+            continue;
+        }
+        if b91.constants.contains(name) {
+            constants.push((name, *value));
+        } else if in_program(*value) {
+            labels.entry(*value).or_default().push(name);
+        }
+    }
+    for names in labels.values_mut() {
+        names.sort();
+    }
+    constants.sort();
+    let mut equs: Vec<(&str, i32)> = Vec::new();
+    for (name, value) in constants {
+        match labels.get_mut(&value) {
+            Some(names) => names.push(name),
+            None => equs.push((name, value)),
+        }
+    }
+    let symbol = |address: i32| labels.get(&address).map(|names| names[0]);
+
+    // (address, text) for each line
+    let mut lines: Vec<(i32, String)> = Vec::new();
+    for (i, word) in code.content.iter().enumerate() {
+        let address = code.start + i as i32;
+        let text = assert_assemblable(*word)
+            .and_then(|_| disassemble_with_symbols(*word, symbol))
+            .map_err(|error| ProgramDecodeError::Instruction { address, error })?;
+        lines.push((address, text));
     }
-    if !opcode.is_classic_isa() {
-        return "N/A".into();
+    let mut i = 0;
+    while i < data.content.len() {
+        // A line continues until the next label or comment, or until zeros change to values.
+        let address = data.start + i as i32;
+        let is_zero = data.content[i] == 0;
+        let mut end = i + 1;
+        while end < data.content.len() && (data.content[end] == 0) == is_zero {
+            let next = data.start + end as i32;
+            if labels.contains_key(&next) || b91.comments.contains_key(&(next as usize)) {
+                break;
+            }
+            end += 1;
+        }
+        let values = &data.content[i..end];
+        let text = match (is_zero, values.len()) {
+            (true, 1) => "DC    0".to_string(),
+            (true, n) => format!("DS    {}", n),
+            (false, _) => format!("DC    {}", values.iter().map(|value| value.to_string()).collect::<Vec<_>>().join(", ")),
+        };
+        lines.push((address, text));
+        i = end;
+    }
+
+    let width = labels.values().flatten().chain(equs.iter().map(|(name, _)| name))
+        .map(|name| name.len()).max().unwrap_or(0).max(6);
+    let mut output = String::new();
+    for (name, value) in equs {
+        output += &format!("{:width$}  EQU   {}\n", name, value);
+    }
+    if code.start != 0 {
+        output += &format!("{:width$}  ORG   {}\n", "", code.start);
+    }
+    for (address, text) in lines {
+        let names = labels.get(&address).map(Vec::as_slice).unwrap_or_default();
+        let mut line = format!("{:width$}  {}", names.first().unwrap_or(&""), text);
+        if let Some(comment) = b91.comments.get(&(address as usize)) {
+            line = format!("{:40} ; {}", line, comment);
+        }
+        output += line.trim_end();
+        output += "\n";
+        for alias in names.iter().skip(1) {
+            output += &format!("{:width$}  EQU   {}\n", alias, names[0]);
+        }
     }
-    disassemble_instruction(input_instr)
+    Ok(output)
 }
 
-fn op2_to_string(mode: i32, ri: Register, addr: i32) -> String {
+/// Check that the instruction can be written in source. Instructions that default to direct
+/// addressing can only go one step further, so their mode 2 has no syntax. Instructions without
+/// an address operand always get the default mode.
+fn assert_assemblable(input_instr: i32) -> Result<(), DecodeError> {
+    let TTK91Instruction { opcode, mode, .. } = TTK91Instruction::decode(input_instr)?;
+    let has_address = matches!(opcode.info().operands, OperandShape::Address | OperandShape::RegisterAddress);
+    let mode = mode as i32;
+    match (has_address, mode - opcode.get_default_mode()) {
+        (true, 2) => Err(DecodeError::InvalidAddressingMode(mode)),
+        (false, offset) if offset != 0 => Err(DecodeError::InvalidAddressingMode(mode)),
+        _ => Ok(()),
+    }
+}
+
+/// Can this symbol be written as a label?
+fn is_label_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    let valid_start = chars.next().is_some_and(|c| c.is_alphabetic() || c == '_');
+    valid_start && chars.all(|c| c.is_alphanumeric() || c == '_' || c == '.') && !is_reserved_name(name)
+}
+
+/// Second operand. Address is written as `name`, if there is one, except when it's implied.
+fn op2_to_string(mode: i32, ri: Register, addr: i32, name: Option<&str>) -> String {
     // -1 is only valid on instructions with default mode 1.
     // 2 is only valid on instructions with default mode 2.
     // 2 results in an @ sign _and_ parentheses.
@@ -79,16 +245,17 @@ fn op2_to_string(mode: i32, ri: Register, addr: i32) -> String {
         2 => "@",
         _ => "‽",
     };
+    let a = name.map(str::to_string).unwrap_or_else(|| addr.to_string());
 
     if ri == Register::R0 {
         if mode == 2 {
-            if addr == 0 {
+            if addr == 0 && name.is_none() {
                 format!("{m}(R0)")
             } else {
-                format!("{m}{addr}(R0)")
+                format!("{m}{a}(R0)")
             }
         } else {
-            format!("{m}{addr}")
+            format!("{m}{a}")
         }
     } else if addr == 0 {
         if mode == 2 {
@@ -97,13 +264,15 @@ fn op2_to_string(mode: i32, ri: Register, addr: i32) -> String {
             format!("{m}{ri}")
         }
     } else {
-        format!("{m}{addr}({ri})")
+        format!("{m}{a}({ri})")
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::compiler::{compile_to_b91, CompileOptions};
+    use crate::instructions::{AddressingMode, OpCode};
     /*
     Addressing modes require some careful testing.
 
@@ -155,4 +324,125 @@ mod tests {
         // "STORE R1, ‽0"
         assert_eq!(disassemble_instruction(20447232).as_str(), "N/A");
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_try_disassemble_instruction() {
+        assert_eq!(try_disassemble_instruction(287309824), Ok("ADD   R1, =0".to_string()));
+        assert_eq!(try_disassemble_instruction(0x5a000000), Err(DecodeError::UnknownOpCode(0x5a)));
+        assert_eq!(try_disassemble_instruction(288882688), Err(DecodeError::InvalidAddressingMode(3)));
+        assert_eq!(try_disassemble_instruction(0x00280000), Err(DecodeError::ReservedBits(0x00200000)));
+        // Lenient version ignores the junk, like execution does.
+        assert_eq!(disassemble_instruction(0x00280000), "NOP  ");
+        assert_eq!(disassemble_instruction(5), "NOP  ");
+
+        // HLT
+        assert_eq!(try_disassemble_instruction(0x71080000), Ok("HLT  ".to_string()));
+        assert_eq!(try_disassemble_instruction_classic(0x71080000), Err(DecodeError::ExtendedOpCode(OpCode::HLT)));
+        assert_eq!(disassemble_instruction_classic(0x71080000), "N/A");
+    }
+
+    /// Compile, disassemble, compile again. Code and data should stay the same.
+    fn assert_reassembles(source: &str) -> String {
+        let options = CompileOptions { constants_section: true, ..Default::default() };
+        let b91 = compile_to_b91(source.to_string(), &options).unwrap();
+        let disassembly = disassemble_program(&b91).unwrap();
+        let again = compile_to_b91(disassembly.clone(), &CompileOptions::default())
+            .unwrap_or_else(|e| panic!("{}\n{}", disassembly, e[0]));
+        assert_eq!(again.code_segment, b91.code_segment, "{}", disassembly);
+        assert_eq!(again.data_segment, b91.data_segment, "{}", disassembly);
+        disassembly
+    }
+
+    #[test]
+    fn test_disassemble_program() {
+        let source = "
+        ORG 2
+        x       DC 5        ; five
+        alias   EQU x
+        size    EQU 3
+        table   DC 1, 2, 3
+        buf     DS 3
+        flag    DC 0
+        main    LOAD R1, x
+                STORE R1, buf(R2)
+        .loop   SUB R1, =1
+                JPOS R1, .loop
+                CALL SP, f
+                SVC SP, =HALT
+        f       PUSH SP, FP ; prologue
+                EXIT SP, =0
+        ";
+        assert_eq!(assert_reassembles(source), "size       EQU   3
+           ORG   2
+main       LOAD  R1,  x
+           STORE R1,  buf(R2)
+main.loop  SUB   R1, =1
+           JPOS  R1,  main.loop
+           CALL  SP,  f
+           SVC   SP, =11
+f          PUSH  SP,  FP                 ; prologue
+           EXIT  SP, =0
+x          DC    5                       ; five
+alias      EQU   x
+table      DC    1, 2, 3
+buf        DS    3
+flag       DC    0
+");
+    }
+
+    #[test]
+    fn test_disassemble_program_errors() {
+        let b91 = B91 {
+            code_segment: crate::b91::B91Segment { start: 0, end: 1, content: vec![0x02200005, 0x5a000000] },
+            ..Default::default()
+        };
+        let error = disassemble_program(&b91).unwrap_err();
+        assert_eq!(error, ProgramDecodeError::Instruction { address: 1, error: DecodeError::UnknownOpCode(0x5a) });
+        assert_eq!(error.to_string(), "Address 1: unknown opcode 0x5A");
+
+        // STORE R1, @@0
+        let b91 = B91 {
+            code_segment: crate::b91::B91Segment { start: 0, end: 0, content: vec![0x01300000] },
+            ..Default::default()
+        };
+        assert_eq!(disassemble_program(&b91).unwrap_err(), ProgramDecodeError::Instruction { address: 0, error: DecodeError::InvalidAddressingMode(2) });
+
+        // Data doesn't start right after code
+        let b91 = B91 {
+            code_segment: crate::b91::B91Segment { start: 0, end: 0, content: vec![0] },
+            data_segment: crate::b91::B91Segment { start: 5, end: 5, content: vec![1] },
+            ..Default::default()
+        };
+        let error = disassemble_program(&b91).unwrap_err();
+        assert_eq!(error, ProgramDecodeError::DataStart { code_end: 1, data_start: 5 });
+        assert_eq!(error.to_string(), "Data segment starts at 5, but code ends at 1");
+    }
+
+    #[test]
+    fn test_disassemble_every_instruction() {
+        let registers = [Register::R0, Register::R1, Register::R2, Register::R3, Register::R4, Register::R5, Register::R6, Register::R7];
+        let modes = [AddressingMode::Immediate, AddressingMode::Direct, AddressingMode::Indirect];
+        let mut words = Vec::new();
+        for opcode in OpCode::iter() {
+            for rj in registers {
+                for mode in modes {
+                    for ri in registers {
+                        for addr in [0, 5, -1] {
+                            let word = TTK91Instruction { opcode, rj, mode, ri, addr }.encode();
+                            if try_disassemble_instruction(word).is_ok() && assert_assemblable(word).is_ok() {
+                                words.push(word);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        let b91 = B91 {
+            code_segment: crate::b91::B91Segment { start: 0, end: words.len() as i32 - 1, content: words.clone() },
+            ..Default::default()
+        };
+        let source = disassemble_program(&b91).unwrap();
+        let b91 = compile_to_b91(source, &CompileOptions::default()).unwrap();
+        assert_eq!(b91.code_segment.content, words);
+    }
+}
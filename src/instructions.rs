@@ -4,20 +4,110 @@
 
 //! TTKTK - TTK-91 ToolKit
 //!
-//! TTK-91 Instructon module. Hosts TTK-91 Instruction struct, and relevant enums.
+//! TTK-91 Instructon module. Hosts TTK-91 Instruction struct, relevant enums, and a table that
+//! describes every opcode.
 //!
 use std::fmt;
 use std::str::FromStr;
 
+/// One TTK-91 instruction word, taken apart.
+///
+/// Bits from most significant: opcode (8), Rj (3), addressing mode (2), Ri (3), address (16).
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct TTK91Instruction {
     pub opcode: OpCode,
     pub rj: Register,
+    /// Addressing mode as it's stored in the word. The compiler adjusts it by the opcode's default
+    /// mode, see [OpCode::get_default_mode].
     pub mode: AddressingMode,
     pub ri: Register,
     pub addr: i16,
 }
 
-#[derive(Copy, Clone, PartialEq)]
+/// Why an instruction word couldn't be decoded.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum DecodeError {
+    /// Opcode doesn't match any instruction. Has the opcode value.
+    UnknownOpCode(i32),
+    /// Addressing mode is 3, or out of range. Has the mode value.
+    InvalidAddressingMode(i32),
+    /// Register number is out of range. Has the register value.
+    InvalidRegister(i32),
+    /// Fields the instruction doesn't use aren't zero. Has a mask of the offending bits.
+    ReservedBits(i32),
+    /// Instruction is from the extended instruction set, but only classic is allowed.
+    ExtendedOpCode(OpCode),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::UnknownOpCode(value) => write!(f, "unknown opcode 0x{:02X}", value),
+            DecodeError::InvalidAddressingMode(value) => write!(f, "invalid addressing mode {}", value),
+            DecodeError::InvalidRegister(value) => write!(f, "invalid register {}", value),
+            DecodeError::ReservedBits(mask) => write!(f, "reserved bits set: 0x{:08X}", mask),
+            DecodeError::ExtendedOpCode(opcode) => write!(f, "{} is not in the classic instruction set", opcode),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl TTK91Instruction {
+    /// Put the instruction together into a word.
+    pub fn encode(&self) -> i32 {
+        let mut value;
+        value = (self.opcode as i32) << 24;
+        value += (self.rj as i32) << 21;
+        value += (self.mode as i32) << 19;
+        value += (self.ri as i32) << 16;
+        value += self.addr as u16 as i32;
+        value
+    }
+
+    /// Take an instruction word apart. Fields the instruction doesn't use are kept as they are, see
+    /// [TTK91Instruction::check_reserved_bits].
+    pub fn decode(value: i32) -> Result<Self, DecodeError> {
+        let opcode = OpCode::try_from((value >> 24) & 0xff)?;
+        let mode = match AddressingMode::try_from((value >> 19) & 0x3)? {
+            AddressingMode::Invalid => return Err(DecodeError::InvalidAddressingMode(3)),
+            mode => mode,
+        };
+        Ok(TTK91Instruction {
+            opcode,
+            // Three bits can't be anything else than a register.
+            rj: Register::try_from((value >> 21) & 0x7).unwrap(),
+            mode,
+            ri: Register::try_from((value >> 16) & 0x7).unwrap(),
+            // These casts catch the sign.
+            addr: (value & 0xffff) as i16,
+        })
+    }
+
+    /// Check that the fields this instruction doesn't use are zero, like the compiler leaves them:
+    /// Rj for jumps that only take an address, Ri and address for instructions that only take a
+    /// register, and all of them for instructions with no operands.
+    pub fn check_reserved_bits(&self) -> Result<(), DecodeError> {
+        let word = self.encode();
+        let rj = 0x7 << 21;
+        let ri_and_addr = (0x7 << 16) | 0xffff;
+        let reserved = match self.opcode.get_operand_count() {
+            0 => rj | ri_and_addr,
+            1 if self.opcode.is_op2_only() => rj,
+            1 => ri_and_addr,
+            _ => 0,
+        };
+        match word & reserved {
+            0 => Ok(()),
+            mask => Err(DecodeError::ReservedBits(mask)),
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Register {
     R0 = 0,
     R1 = 1,
@@ -29,7 +119,8 @@ pub enum Register {
     R7 = 7,
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum AddressingMode {
     Immediate = 0,
     Direct = 1,
@@ -37,7 +128,8 @@ pub enum AddressingMode {
     Invalid = 3,
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum OpCode {
     // Standard
     NOP = 0x00,
@@ -85,355 +177,260 @@ pub enum OpCode {
     HCF = 0x72,
 }
 
-impl TryFrom<i32> for OpCode {
-    type Error = ();
+impl TryFrom<i32> for AddressingMode {
+    type Error = DecodeError;
     fn try_from(value: i32) -> Result<Self, Self::Error> {
         match value {
-            0x00 => Ok(OpCode::NOP),
-            0x01 => Ok(OpCode::STORE),
-            0x02 => Ok(OpCode::LOAD),
-            0x03 => Ok(OpCode::IN),
-            0x04 => Ok(OpCode::OUT),
-            0x11 => Ok(OpCode::ADD),
-            0x12 => Ok(OpCode::SUB),
-            0x13 => Ok(OpCode::MUL),
-            0x14 => Ok(OpCode::DIV),
-            0x15 => Ok(OpCode::MOD),
-            0x16 => Ok(OpCode::AND),
-            0x17 => Ok(OpCode::OR),
-            0x18 => Ok(OpCode::XOR),
-            0x19 => Ok(OpCode::SHL),
-            0x1A => Ok(OpCode::SHR),
-            0x1B => Ok(OpCode::NOT),
-            0x1C => Ok(OpCode::SHRA),
-            0x1F => Ok(OpCode::COMP),
-            0x20 => Ok(OpCode::JUMP),
-            0x21 => Ok(OpCode::JNEG),
-            0x22 => Ok(OpCode::JZER),
-            0x23 => Ok(OpCode::JPOS),
-            0x24 => Ok(OpCode::JNNEG),
-            0x25 => Ok(OpCode::JNZER),
-            0x26 => Ok(OpCode::JNPOS),
-            0x27 => Ok(OpCode::JLES),
-            0x28 => Ok(OpCode::JEQU),
-            0x29 => Ok(OpCode::JGRE),
-            0x2A => Ok(OpCode::JNLES),
-            0x2B => Ok(OpCode::JNEQU),
-            0x2C => Ok(OpCode::JNGRE),
-            0x31 => Ok(OpCode::CALL),
-            0x32 => Ok(OpCode::EXIT),
-            0x33 => Ok(OpCode::PUSH),
-            0x34 => Ok(OpCode::POP),
-            0x35 => Ok(OpCode::PUSHR),
-            0x36 => Ok(OpCode::POPR),
-            0x70 => Ok(OpCode::SVC),
-            // Extended
-            0x39 => Ok(OpCode::IEXIT),
-            0x71 => Ok(OpCode::HLT),
-            0x72 => Ok(OpCode::HCF),
-            _ => Err(())
+            0 => Ok(AddressingMode::Immediate),
+            1 => Ok(AddressingMode::Direct),
+            2 => Ok(AddressingMode::Indirect),
+            3 => Ok(AddressingMode::Invalid),
+            _ => Err(DecodeError::InvalidAddressingMode(value)),
         }
     }
 }
 
+impl TryFrom<i32> for OpCode {
+    type Error = DecodeError;
+    fn try_from(value: i32) -> Result<Self, Self::Error> {
+        usize::try_from(value).ok()
+            .and_then(|value| OPCODE_INDEX.get(value))
+            .and_then(|&index| OPCODES.get(index as usize))
+            .map(|info| info.opcode)
+            .ok_or(DecodeError::UnknownOpCode(value))
+    }
+}
+
 impl FromStr for OpCode {
     type Err = String;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s.to_uppercase().as_str() {
-            "NOP" => Ok(OpCode::NOP),
-            "STORE" => Ok(OpCode::STORE),
-            "LOAD" => Ok(OpCode::LOAD),
-            "IN" => Ok(OpCode::IN),
-            "OUT" => Ok(OpCode::OUT),
-            "ADD" => Ok(OpCode::ADD),
-            "SUB" => Ok(OpCode::SUB),
-            "MUL" => Ok(OpCode::MUL),
-            "DIV" => Ok(OpCode::DIV),
-            "MOD" => Ok(OpCode::MOD),
-            "AND" => Ok(OpCode::AND),
-            "OR" => Ok(OpCode::OR),
-            "XOR" => Ok(OpCode::XOR),
-            "SHL" => Ok(OpCode::SHL),
-            "SHR" => Ok(OpCode::SHR),
-            "NOT" => Ok(OpCode::NOT),
-            "SHRA" => Ok(OpCode::SHRA),
-            "COMP" => Ok(OpCode::COMP),
-            "JUMP" => Ok(OpCode::JUMP),
-            "JNEG" => Ok(OpCode::JNEG),
-            "JZER" => Ok(OpCode::JZER),
-            "JPOS" => Ok(OpCode::JPOS),
-            "JNNEG" => Ok(OpCode::JNNEG),
-            "JNZER" => Ok(OpCode::JNZER),
-            "JNPOS" => Ok(OpCode::JNPOS),
-            "JLES" => Ok(OpCode::JLES),
-            "JEQU" => Ok(OpCode::JEQU),
-            "JGRE" => Ok(OpCode::JGRE),
-            "JNLES" => Ok(OpCode::JNLES),
-            "JNEQU" => Ok(OpCode::JNEQU),
-            "JNGRE" => Ok(OpCode::JNGRE),
-            "CALL" => Ok(OpCode::CALL),
-            "EXIT" => Ok(OpCode::EXIT),
-            "PUSH" => Ok(OpCode::PUSH),
-            "POP" => Ok(OpCode::POP),
-            "PUSHR" => Ok(OpCode::PUSHR),
-            "POPR" => Ok(OpCode::POPR),
-            "SVC" => Ok(OpCode::SVC),
-            // Extended
-            "IEXIT" => Ok(OpCode::IEXIT),
-            "HLT" => Ok(OpCode::HLT),
-            "HCF" => Ok(OpCode::HCF),
-            _ => return Err(format!("{} is not an instruction.", s)),
-        }
+        let mnemonic = s.to_uppercase();
+        OPCODES.iter()
+            .find(|info| info.mnemonic == mnemonic)
+            .map(|info| info.opcode)
+            .ok_or_else(|| format!("{} is not an instruction.", s))
     }
 }
 
 impl fmt::Display for OpCode {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            OpCode::NOP => write!(f, "NOP"),
-            OpCode::STORE => write!(f, "STORE"),
-            OpCode::LOAD => write!(f, "LOAD"),
-            OpCode::IN => write!(f, "IN"),
-            OpCode::OUT => write!(f, "OUT"),
-            OpCode::ADD => write!(f, "ADD"),
-            OpCode::SUB => write!(f, "SUB"),
-            OpCode::MUL => write!(f, "MUL"),
-            OpCode::DIV => write!(f, "DIV"),
-            OpCode::MOD => write!(f, "MOD"),
-            OpCode::AND => write!(f, "AND"),
-            OpCode::OR => write!(f, "OR"),
-            OpCode::XOR => write!(f, "XOR"),
-            OpCode::SHL => write!(f, "SHL"),
-            OpCode::SHR => write!(f, "SHR"),
-            OpCode::NOT => write!(f, "NOT"),
-            OpCode::SHRA => write!(f, "SHRA"),
-            OpCode::COMP => write!(f, "COMP"),
-            OpCode::JUMP => write!(f, "JUMP"),
-            OpCode::JNEG => write!(f, "JNEG"),
-            OpCode::JZER => write!(f, "JZER"),
-            OpCode::JPOS => write!(f, "JPOS"),
-            OpCode::JNNEG => write!(f, "JNNEG"),
-            OpCode::JNZER => write!(f, "JNZER"),
-            OpCode::JNPOS => write!(f, "JNPOS"),
-            OpCode::JLES => write!(f, "JLES"),
-            OpCode::JEQU => write!(f, "JEQU"),
-            OpCode::JGRE => write!(f, "JGRE"),
-            OpCode::JNLES => write!(f, "JNLES"),
-            OpCode::JNEQU => write!(f, "JNEQU"),
-            OpCode::JNGRE => write!(f, "JNGRE"),
-            OpCode::CALL => write!(f, "CALL"),
-            OpCode::EXIT => write!(f, "EXIT"),
-            OpCode::PUSH => write!(f, "PUSH"),
-            OpCode::POP => write!(f, "POP"),
-            OpCode::PUSHR => write!(f, "PUSHR"),
-            OpCode::POPR => write!(f, "POPR"),
-            OpCode::SVC => write!(f, "SVC"),
-            // Extended
-            OpCode::IEXIT => write!(f, "IEXIT"),
-            OpCode::HLT => write!(f, "HLT"),
-            OpCode::HCF => write!(f, "HCF"),
-        }
+        write!(f, "{}", self.info().mnemonic)
     }
 }
 
 impl OpCode {
+    /// Every opcode, in order of value.
+    pub fn iter() -> impl Iterator<Item = OpCode> {
+        OPCODES.iter().map(|info| info.opcode)
+    }
+
+    /// Everything there is to know about this opcode.
+    pub fn info(&self) -> &'static OpCodeInfo {
+        &OPCODES[OPCODE_INDEX[*self as usize] as usize]
+    }
+
     /// How many operands does this opcode expect?
     pub fn get_operand_count(&self) -> usize {
-        match self {
-            OpCode::NOP => 0,
-            OpCode::STORE => 2,
-            OpCode::LOAD => 2,
-            OpCode::IN => 2,
-            OpCode::OUT => 2,
-            OpCode::ADD => 2,
-            OpCode::SUB => 2,
-            OpCode::MUL => 2,
-            OpCode::DIV => 2,
-            OpCode::MOD => 2,
-            OpCode::AND => 2,
-            OpCode::OR => 2,
-            OpCode::XOR => 2,
-            OpCode::SHL => 2,
-            OpCode::SHR => 2,
-            OpCode::NOT => 1,
-            OpCode::SHRA => 2,
-            OpCode::COMP => 2,
-            OpCode::JUMP => 1,
-            OpCode::JNEG => 2,
-            OpCode::JZER => 2,
-            OpCode::JPOS => 2,
-            OpCode::JNNEG => 2,
-            OpCode::JNZER => 2,
-            OpCode::JNPOS => 2,
-            OpCode::JLES => 1,
-            OpCode::JEQU => 1,
-            OpCode::JGRE => 1,
-            OpCode::JNLES => 1,
-            OpCode::JNEQU => 1,
-            OpCode::JNGRE => 1,
-            OpCode::CALL => 2,
-            OpCode::EXIT => 2,
-            OpCode::PUSH => 2,
-            OpCode::POP => 2,
-            OpCode::PUSHR => 1,
-            OpCode::POPR => 1,
-            OpCode::SVC => 2,
-            // Extended
-            OpCode::IEXIT => 2,
-            OpCode::HLT => 0,
-            OpCode::HCF => 0,
-        }
+        self.info().operands.count()
     }
 
     /// What is the default mode for this opcode?
     /// Usually 1, but some instructions _require_ operating on a memory address, in which case it
     /// is 0.
     pub fn get_default_mode(&self) -> i32 {
-        match self {
-            OpCode::NOP => 1,
-            OpCode::STORE => 0,
-            OpCode::LOAD => 1,
-            OpCode::IN => 1,
-            OpCode::OUT => 1,
-            OpCode::ADD => 1,
-            OpCode::SUB => 1,
-            OpCode::MUL => 1,
-            OpCode::DIV => 1,
-            OpCode::MOD => 1,
-            OpCode::AND => 1,
-            OpCode::OR => 1,
-            OpCode::XOR => 1,
-            OpCode::SHL => 1,
-            OpCode::SHR => 1,
-            OpCode::NOT => 1,
-            OpCode::SHRA => 1,
-            OpCode::COMP => 1,
-            OpCode::JUMP => 0,
-            OpCode::JNEG => 0,
-            OpCode::JZER => 0,
-            OpCode::JPOS => 0,
-            OpCode::JNNEG => 0,
-            OpCode::JNZER => 0,
-            OpCode::JNPOS => 0,
-            OpCode::JLES => 0,
-            OpCode::JEQU => 0,
-            OpCode::JGRE => 0,
-            OpCode::JNLES => 0,
-            OpCode::JNEQU => 0,
-            OpCode::JNGRE => 0,
-            OpCode::CALL => 0,
-            OpCode::EXIT => 1,
-            OpCode::PUSH => 1,
-            OpCode::POP => 1,
-            OpCode::PUSHR => 1,
-            OpCode::POPR => 1,
-            OpCode::SVC => 1,
-            // Extended
-            OpCode::IEXIT => 1,
-            OpCode::HLT => 1,
-            OpCode::HCF => 1,
-        }
+        self.info().default_mode
     }
 
     /// Special case: First operand is _not_ expexted.
     /// Applies to JUMP and State Register using jumps.
     pub fn is_op2_only(&self) -> bool {
-        match self {
-            OpCode::NOP => false,
-            OpCode::STORE => false,
-            OpCode::LOAD => false,
-            OpCode::IN => false,
-            OpCode::OUT => false,
-            OpCode::ADD => false,
-            OpCode::SUB => false,
-            OpCode::MUL => false,
-            OpCode::DIV => false,
-            OpCode::MOD => false,
-            OpCode::AND => false,
-            OpCode::OR => false,
-            OpCode::XOR => false,
-            OpCode::SHL => false,
-            OpCode::SHR => false,
-            OpCode::NOT => false,
-            OpCode::SHRA => false,
-            OpCode::COMP => false,
-            OpCode::JUMP => true,
-            OpCode::JNEG => false,
-            OpCode::JZER => false,
-            OpCode::JPOS => false,
-            OpCode::JNNEG => false,
-            OpCode::JNZER => false,
-            OpCode::JNPOS => false,
-            OpCode::JLES => true,
-            OpCode::JEQU => true,
-            OpCode::JGRE => true,
-            OpCode::JNLES => true,
-            OpCode::JNEQU => true,
-            OpCode::JNGRE => true,
-            OpCode::CALL => false,
-            OpCode::EXIT => false,
-            OpCode::PUSH => false,
-            OpCode::POP => false,
-            OpCode::PUSHR => false,
-            OpCode::POPR => false,
-            OpCode::SVC => false,
-            // Extended
-            OpCode::IEXIT => false,
-            OpCode::HLT => false,
-            OpCode::HCF => false,
-        }
+        self.info().operands == OperandShape::Address
     }
 
     /// If you're only interested in the "classic" backwards-compatible instruction set and want to
     /// block or ignore titomachine's extended instructions, you can use this to check.
     pub fn is_classic_isa(&self) -> bool {
+        self.info().isa == IsaFamily::Classic
+    }
+}
+
+/// Which operands an instruction takes.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum OperandShape {
+    /// "NOP"
+    None,
+    /// First operand only: "NOT R1"
+    Register,
+    /// Second operand only: "JUMP addr"
+    Address,
+    /// Both: "LOAD R1, addr"
+    RegisterAddress,
+}
+
+impl OperandShape {
+    /// Number of operands written in source.
+    pub fn count(&self) -> usize {
         match self {
-            OpCode::NOP => true,
-            OpCode::STORE => true,
-            OpCode::LOAD => true,
-            OpCode::IN => true,
-            OpCode::OUT => true,
-            OpCode::ADD => true,
-            OpCode::SUB => true,
-            OpCode::MUL => true,
-            OpCode::DIV => true,
-            OpCode::MOD => true,
-            OpCode::AND => true,
-            OpCode::OR => true,
-            OpCode::XOR => true,
-            OpCode::SHL => true,
-            OpCode::SHR => true,
-            OpCode::NOT => true,
-            OpCode::SHRA => true,
-            OpCode::COMP => true,
-            OpCode::JUMP => true,
-            OpCode::JNEG => true,
-            OpCode::JZER => true,
-            OpCode::JPOS => true,
-            OpCode::JNNEG => true,
-            OpCode::JNZER => true,
-            OpCode::JNPOS => true,
-            OpCode::JLES => true,
-            OpCode::JEQU => true,
-            OpCode::JGRE => true,
-            OpCode::JNLES => true,
-            OpCode::JNEQU => true,
-            OpCode::JNGRE => true,
-            OpCode::CALL => true,
-            OpCode::EXIT => true,
-            OpCode::PUSH => true,
-            OpCode::POP => true,
-            OpCode::PUSHR => true,
-            OpCode::POPR => true,
-            OpCode::SVC => true,
-            // Extended
-            OpCode::IEXIT => false,
-            OpCode::HLT => false,
-            OpCode::HCF => false,
+            OperandShape::None => 0,
+            OperandShape::Register | OperandShape::Address => 1,
+            OperandShape::RegisterAddress => 2,
         }
     }
 }
 
+/// Instruction set an opcode belongs to.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum IsaFamily {
+    /// Original TTK-91, as understood by Titokone.
+    Classic,
+    /// TiToMachine extensions.
+    Extended,
+}
+
+/// State register flags.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum StateFlag {
+    /// G: Comparison result was greater.
+    Greater,
+    /// E: Comparison result was equal.
+    Equal,
+    /// L: Comparison result was less.
+    Less,
+    /// O: Arithmetic overflow.
+    Overflow,
+    /// Z: Division by zero.
+    ZeroDivision,
+    /// U: Unknown instruction.
+    UnknownInstruction,
+    /// M: Forbidden memory address.
+    ForbiddenAddress,
+    /// I: Device interrupt.
+    DeviceInterrupt,
+    /// S: Supervisor call.
+    SupervisorCall,
+    /// P: Privileged mode.
+    Privileged,
+    /// D: Interrupts disabled.
+    InterruptsDisabled,
+}
+
+/// Memory the instruction itself accesses, not counting fetching the second operand.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum MemoryAccess {
+    None,
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl MemoryAccess {
+    pub fn reads(&self) -> bool {
+        matches!(self, MemoryAccess::Read | MemoryAccess::ReadWrite)
+    }
+
+    pub fn writes(&self) -> bool {
+        matches!(self, MemoryAccess::Write | MemoryAccess::ReadWrite)
+    }
+}
+
+/// Description of an opcode. See [OPCODES].
+/// Only serializable, since it borrows static strings.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct OpCodeInfo {
+    pub opcode: OpCode,
+    /// Name in source, uppercase.
+    pub mnemonic: &'static str,
+    /// Value in the top 8 bits of an instruction word.
+    pub value: i32,
+    pub operands: OperandShape,
+    /// See [OpCode::get_default_mode].
+    pub default_mode: i32,
+    pub isa: IsaFamily,
+    /// What the instruction does, in a few words.
+    pub description: &'static str,
+    /// State register flags the instruction may set as a result. Memory faults and the like, which
+    /// any instruction can cause, aren't listed.
+    pub flags: &'static [StateFlag],
+    pub memory: MemoryAccess,
+}
+
+impl OpCodeInfo {
+    #[allow(clippy::too_many_arguments)]
+    const fn new(
+        opcode: OpCode,
+        mnemonic: &'static str,
+        operands: OperandShape,
+        default_mode: i32,
+        isa: IsaFamily,
+        flags: &'static [StateFlag],
+        memory: MemoryAccess,
+        description: &'static str,
+    ) -> Self {
+        OpCodeInfo { opcode, mnemonic, value: opcode as i32, operands, default_mode, isa, description, flags, memory }
+    }
+}
+
+/// Every opcode, in order of value.
+pub static OPCODES: [OpCodeInfo; 41] = [
+    OpCodeInfo::new(OpCode::NOP, "NOP", OperandShape::None, 1, IsaFamily::Classic, &[], MemoryAccess::None, "No operation"),
+    OpCodeInfo::new(OpCode::STORE, "STORE", OperandShape::RegisterAddress, 0, IsaFamily::Classic, &[], MemoryAccess::Write, "Store register to memory"),
+    OpCodeInfo::new(OpCode::LOAD, "LOAD", OperandShape::RegisterAddress, 1, IsaFamily::Classic, &[], MemoryAccess::None, "Load value to register"),
+    OpCodeInfo::new(OpCode::IN, "IN", OperandShape::RegisterAddress, 1, IsaFamily::Classic, &[], MemoryAccess::None, "Read from device"),
+    OpCodeInfo::new(OpCode::OUT, "OUT", OperandShape::RegisterAddress, 1, IsaFamily::Classic, &[], MemoryAccess::None, "Write to device"),
+    OpCodeInfo::new(OpCode::ADD, "ADD", OperandShape::RegisterAddress, 1, IsaFamily::Classic, &[StateFlag::Overflow], MemoryAccess::None, "Add"),
+    OpCodeInfo::new(OpCode::SUB, "SUB", OperandShape::RegisterAddress, 1, IsaFamily::Classic, &[StateFlag::Overflow], MemoryAccess::None, "Subtract"),
+    OpCodeInfo::new(OpCode::MUL, "MUL", OperandShape::RegisterAddress, 1, IsaFamily::Classic, &[StateFlag::Overflow], MemoryAccess::None, "Multiply"),
+    OpCodeInfo::new(OpCode::DIV, "DIV", OperandShape::RegisterAddress, 1, IsaFamily::Classic, &[StateFlag::Overflow, StateFlag::ZeroDivision], MemoryAccess::None, "Divide"),
+    OpCodeInfo::new(OpCode::MOD, "MOD", OperandShape::RegisterAddress, 1, IsaFamily::Classic, &[StateFlag::ZeroDivision], MemoryAccess::None, "Remainder"),
+    OpCodeInfo::new(OpCode::AND, "AND", OperandShape::RegisterAddress, 1, IsaFamily::Classic, &[], MemoryAccess::None, "Bitwise AND"),
+    OpCodeInfo::new(OpCode::OR, "OR", OperandShape::RegisterAddress, 1, IsaFamily::Classic, &[], MemoryAccess::None, "Bitwise OR"),
+    OpCodeInfo::new(OpCode::XOR, "XOR", OperandShape::RegisterAddress, 1, IsaFamily::Classic, &[], MemoryAccess::None, "Bitwise XOR"),
+    OpCodeInfo::new(OpCode::SHL, "SHL", OperandShape::RegisterAddress, 1, IsaFamily::Classic, &[], MemoryAccess::None, "Shift left"),
+    OpCodeInfo::new(OpCode::SHR, "SHR", OperandShape::RegisterAddress, 1, IsaFamily::Classic, &[], MemoryAccess::None, "Shift right"),
+    OpCodeInfo::new(OpCode::NOT, "NOT", OperandShape::Register, 1, IsaFamily::Classic, &[], MemoryAccess::None, "Bitwise NOT"),
+    OpCodeInfo::new(OpCode::SHRA, "SHRA", OperandShape::RegisterAddress, 1, IsaFamily::Classic, &[], MemoryAccess::None, "Arithmetic shift right"),
+    OpCodeInfo::new(OpCode::COMP, "COMP", OperandShape::RegisterAddress, 1, IsaFamily::Classic, &[StateFlag::Greater, StateFlag::Equal, StateFlag::Less], MemoryAccess::None, "Compare"),
+    OpCodeInfo::new(OpCode::JUMP, "JUMP", OperandShape::Address, 0, IsaFamily::Classic, &[], MemoryAccess::None, "Jump"),
+    OpCodeInfo::new(OpCode::JNEG, "JNEG", OperandShape::RegisterAddress, 0, IsaFamily::Classic, &[], MemoryAccess::None, "Jump if register is negative"),
+    OpCodeInfo::new(OpCode::JZER, "JZER", OperandShape::RegisterAddress, 0, IsaFamily::Classic, &[], MemoryAccess::None, "Jump if register is zero"),
+    OpCodeInfo::new(OpCode::JPOS, "JPOS", OperandShape::RegisterAddress, 0, IsaFamily::Classic, &[], MemoryAccess::None, "Jump if register is positive"),
+    OpCodeInfo::new(OpCode::JNNEG, "JNNEG", OperandShape::RegisterAddress, 0, IsaFamily::Classic, &[], MemoryAccess::None, "Jump if register is not negative"),
+    OpCodeInfo::new(OpCode::JNZER, "JNZER", OperandShape::RegisterAddress, 0, IsaFamily::Classic, &[], MemoryAccess::None, "Jump if register is not zero"),
+    OpCodeInfo::new(OpCode::JNPOS, "JNPOS", OperandShape::RegisterAddress, 0, IsaFamily::Classic, &[], MemoryAccess::None, "Jump if register is not positive"),
+    OpCodeInfo::new(OpCode::JLES, "JLES", OperandShape::Address, 0, IsaFamily::Classic, &[], MemoryAccess::None, "Jump if comparison was less"),
+    OpCodeInfo::new(OpCode::JEQU, "JEQU", OperandShape::Address, 0, IsaFamily::Classic, &[], MemoryAccess::None, "Jump if comparison was equal"),
+    OpCodeInfo::new(OpCode::JGRE, "JGRE", OperandShape::Address, 0, IsaFamily::Classic, &[], MemoryAccess::None, "Jump if comparison was greater"),
+    OpCodeInfo::new(OpCode::JNLES, "JNLES", OperandShape::Address, 0, IsaFamily::Classic, &[], MemoryAccess::None, "Jump if comparison was not less"),
+    OpCodeInfo::new(OpCode::JNEQU, "JNEQU", OperandShape::Address, 0, IsaFamily::Classic, &[], MemoryAccess::None, "Jump if comparison was not equal"),
+    OpCodeInfo::new(OpCode::JNGRE, "JNGRE", OperandShape::Address, 0, IsaFamily::Classic, &[], MemoryAccess::None, "Jump if comparison was not greater"),
+    OpCodeInfo::new(OpCode::CALL, "CALL", OperandShape::RegisterAddress, 0, IsaFamily::Classic, &[], MemoryAccess::Write, "Call subroutine"),
+    OpCodeInfo::new(OpCode::EXIT, "EXIT", OperandShape::RegisterAddress, 1, IsaFamily::Classic, &[], MemoryAccess::Read, "Return from subroutine"),
+    OpCodeInfo::new(OpCode::PUSH, "PUSH", OperandShape::RegisterAddress, 1, IsaFamily::Classic, &[], MemoryAccess::Write, "Push to stack"),
+    OpCodeInfo::new(OpCode::POP, "POP", OperandShape::RegisterAddress, 1, IsaFamily::Classic, &[], MemoryAccess::Read, "Pop from stack"),
+    OpCodeInfo::new(OpCode::PUSHR, "PUSHR", OperandShape::Register, 1, IsaFamily::Classic, &[], MemoryAccess::Write, "Push registers R0-R6 to stack"),
+    OpCodeInfo::new(OpCode::POPR, "POPR", OperandShape::Register, 1, IsaFamily::Classic, &[], MemoryAccess::Read, "Pop registers R6-R0 from stack"),
+    OpCodeInfo::new(OpCode::IEXIT, "IEXIT", OperandShape::RegisterAddress, 1, IsaFamily::Extended, &[], MemoryAccess::Read, "Return from interrupt"),
+    OpCodeInfo::new(OpCode::SVC, "SVC", OperandShape::RegisterAddress, 1, IsaFamily::Classic, &[StateFlag::SupervisorCall], MemoryAccess::Write, "Supervisor call"),
+    OpCodeInfo::new(OpCode::HLT, "HLT", OperandShape::None, 1, IsaFamily::Extended, &[], MemoryAccess::None, "Halt"),
+    OpCodeInfo::new(OpCode::HCF, "HCF", OperandShape::None, 1, IsaFamily::Extended, &[], MemoryAccess::None, "Halt and catch fire"),
+];
+
+/// Position of each opcode in [OPCODES], by opcode value. Unused values are `u8::MAX`.
+static OPCODE_INDEX: [u8; 256] = {
+    let mut index = [u8::MAX; 256];
+    let mut i = 0;
+    while i < OPCODES.len() {
+        index[OPCODES[i].value as usize] = i as u8;
+        i += 1;
+    }
+    index
+};
+
 impl FromStr for Register {
     type Err = String;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
@@ -467,7 +464,7 @@ impl fmt::Display for Register {
 }
 
 impl TryFrom<i32> for Register {
-    type Error = ();
+    type Error = DecodeError;
     fn try_from(value: i32) -> Result<Self, Self::Error> {
         match value {
             0 => Ok(Register::R0),
@@ -478,7 +475,134 @@ impl TryFrom<i32> for Register {
             5 => Ok(Register::R5),
             6 => Ok(Register::R6),
             7 => Ok(Register::R7),
-            _ => Err(()),
+            _ => Err(DecodeError::InvalidRegister(value)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use super::*;
+
+    const REGISTERS: [Register; 8] = [Register::R0, Register::R1, Register::R2, Register::R3, Register::R4, Register::R5, Register::R6, Register::R7];
+    const MODES: [AddressingMode; 3] = [AddressingMode::Immediate, AddressingMode::Direct, AddressingMode::Indirect];
+    const ADDRESSES: [i16; 6] = [0, 1, -1, 0x1234, i16::MIN, i16::MAX];
+
+    #[test]
+    fn test_opcode_table() {
+        assert_eq!(OpCode::iter().count(), OPCODES.len());
+        for (i, info) in OPCODES.iter().enumerate() {
+            assert_eq!(info.mnemonic, format!("{:?}", info.opcode));
+            assert_eq!(info.value, info.opcode as i32);
+            assert_eq!(OpCode::try_from(info.value), Ok(info.opcode));
+            assert_eq!(OpCode::from_str(&info.mnemonic.to_lowercase()), Ok(info.opcode));
+            assert_eq!(info.opcode.to_string(), info.mnemonic);
+            assert!(i == 0 || OPCODES[i - 1].value < info.value, "{} is out of order", info.mnemonic);
+        }
+        for value in [0x05, 0xFF, 0x100, -1] {
+            assert_eq!(OpCode::try_from(value), Err(DecodeError::UnknownOpCode(value)));
+        }
+
+        let info = OpCode::COMP.info();
+        assert_eq!(info.flags, [StateFlag::Greater, StateFlag::Equal, StateFlag::Less]);
+        assert!(OpCode::STORE.info().memory.writes());
+        assert!(!OpCode::STORE.info().memory.reads());
+        assert_eq!(OpCode::JUMP.get_operand_count(), 1);
+        assert!(OpCode::JUMP.is_op2_only());
+        assert!(!OpCode::HCF.is_classic_isa());
+        assert_eq!(OpCode::iter().filter(|opcode| !opcode.is_classic_isa()).count(), 3);
+    }
+
+    #[test]
+    fn test_opcode_as_key() {
+        let mut counts: HashMap<OpCode, usize> = HashMap::new();
+        for word in [0x02200005, 0x02400001, 0x71080000] {
+            *counts.entry(TTK91Instruction::decode(word).unwrap().opcode).or_default() += 1;
+        }
+        assert_eq!(counts[&OpCode::LOAD], 2);
+        assert_eq!(counts[&OpCode::HLT], 1);
+        assert!(Register::R1 < Register::R6);
+    }
+
+    #[test]
+    fn test_encode() {
+        let instruction = TTK91Instruction {
+            opcode: OpCode::ADD,
+            rj: Register::R1,
+            mode: AddressingMode::Immediate,
+            ri: Register::R0,
+            addr: 0,
+        };
+        assert_eq!(instruction.encode(), 287309824);
+        let instruction = TTK91Instruction {
+            opcode: OpCode::STORE,
+            rj: Register::R1,
+            mode: AddressingMode::Indirect,
+            ri: Register::R0,
+            addr: 1,
+        };
+        assert_eq!(instruction.encode(), 19922945);
+        let instruction = TTK91Instruction {
+            opcode: OpCode::LOAD,
+            rj: Register::R1,
+            mode: AddressingMode::Immediate,
+            ri: Register::R0,
+            addr: -1,
+        };
+        assert_eq!(instruction.encode(), 0x0220ffff);
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        assert_eq!(TTK91Instruction::decode(0x05000000).err(), Some(DecodeError::UnknownOpCode(0x05)));
+        assert_eq!(TTK91Instruction::decode(-1).err(), Some(DecodeError::UnknownOpCode(0xff)));
+        assert_eq!(TTK91Instruction::decode(288882688).err(), Some(DecodeError::InvalidAddressingMode(3)));
+
+        assert_eq!(Register::try_from(8).err(), Some(DecodeError::InvalidRegister(8)));
+        assert_eq!(AddressingMode::try_from(4).err(), Some(DecodeError::InvalidAddressingMode(4)));
+        assert_eq!(DecodeError::UnknownOpCode(0x5a).to_string(), "unknown opcode 0x5A");
+        assert_eq!(DecodeError::ExtendedOpCode(OpCode::HLT).to_string(), "HLT is not in the classic instruction set");
+    }
+
+    #[test]
+    fn test_check_reserved_bits() {
+        let check = |word: i32| TTK91Instruction::decode(word).unwrap().check_reserved_bits();
+        // NOP with R1
+        assert_eq!(check(0x00280000), Err(DecodeError::ReservedBits(0x00200000)));
+        // HLT with an address
+        assert_eq!(check(0x71080005), Err(DecodeError::ReservedBits(0x00000005)));
+        // JUMP R1, 5
+        assert_eq!(check(0x20200005), Err(DecodeError::ReservedBits(0x00200000)));
+        // NOT R1, (R2)
+        assert_eq!(check(0x1b2a0000), Err(DecodeError::ReservedBits(0x00020000)));
+        // ADD R1, =0
+        assert_eq!(check(287309824), Ok(()));
+    }
+
+    #[test]
+    fn test_encode_decode_round_trip() {
+        let mut opcodes = 0;
+        for value in 0..=0xff {
+            let Ok(opcode) = OpCode::try_from(value) else {
+                assert_eq!(TTK91Instruction::decode(value << 24).err(), Some(DecodeError::UnknownOpCode(value)));
+                continue;
+            };
+            opcodes += 1;
+            for rj in REGISTERS {
+                for mode in MODES {
+                    for ri in REGISTERS {
+                        for addr in ADDRESSES {
+                            let instruction = TTK91Instruction { opcode, rj, mode, ri, addr };
+                            let word = instruction.encode();
+                            let decoded = TTK91Instruction::decode(word).unwrap();
+                            assert_eq!(decoded, instruction);
+                            assert_eq!(decoded.encode(), word);
+                        }
+                    }
+                }
+            }
         }
+        assert_eq!(opcodes, 41);
     }
 }